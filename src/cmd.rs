@@ -1,16 +1,30 @@
-mod backup;
+pub mod backup;
+mod cat;
 mod init;
 mod list;
 mod mount;
+mod parity;
+mod repack;
+mod repair;
+pub mod restore;
+mod resync;
+mod verify;
 
 use clap::{Args, Parser, Subcommand};
 use eyre::Result;
 use std::{path::PathBuf, process::exit};
 
+/// Exit status when a backup was saved, but some entries could not be read.
+const EXIT_INCOMPLETE_BACKUP: i32 = 3;
+
 #[derive(Args)]
 pub struct GlobalArgs {
-    #[arg(short, help = "Vault Directory", global = true)]
+    #[arg(short, help = "Vault Directory, or s3://BUCKET/PREFIX", global = true)]
     vault_dir: Option<PathBuf>,
+
+    /// Key file that unlocks an encrypted vault, or encrypts a new one
+    #[arg(long, global = true, env = "VAULT_KEY_FILE")]
+    key_file: Option<PathBuf>,
 }
 
 #[derive(Parser)]
@@ -26,13 +40,23 @@ pub struct Cli {
 enum SubCmd {
     Init(init::CliArgs),
     Backup(backup::CliArgs),
+    Cat(cat::CliArgs),
     List(list::CliArgs),
     Mount(mount::CliArgs),
+    Parity(parity::CliArgs),
+    Repack(repack::CliArgs),
+    Repair(repair::CliArgs),
+    Resync(resync::CliArgs),
+    Restore(restore::CliArgs),
+    Verify(verify::CliArgs),
 }
 
 pub fn cli_main() -> ! {
     if let Err(e) = run_cli(Cli::parse()) {
         eprintln!("[error] {e:#}");
+        if e.is::<backup::IncompleteBackup>() {
+            exit(EXIT_INCOMPLETE_BACKUP)
+        }
         exit(1)
     } else {
         exit(0)
@@ -55,7 +79,14 @@ fn run_cli(cli: Cli) -> Result<()> {
     match subcommand {
         SubCmd::Init(args) => init::run(global_args, args),
         SubCmd::Backup(args) => backup::run(global_args, args),
+        SubCmd::Cat(args) => cat::run(global_args, args),
         SubCmd::List(args) => list::run(global_args, args),
         SubCmd::Mount(args) => mount::run(global_args, args),
+        SubCmd::Parity(args) => parity::run(global_args, args),
+        SubCmd::Repack(args) => repack::run(global_args, args),
+        SubCmd::Repair(args) => repair::run(global_args, args),
+        SubCmd::Resync(args) => resync::run(global_args, args),
+        SubCmd::Restore(args) => restore::run(global_args, args),
+        SubCmd::Verify(args) => verify::run(global_args, args),
     }
 }
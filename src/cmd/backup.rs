@@ -1,111 +1,904 @@
+mod exclude;
+
 use clap::Args;
-use eyre::{bail, Result};
+use eyre::{bail, ensure, Context, ContextCompat, Result};
+use path_absolutize::Absolutize;
 use std::{
-    fs::{read_link, symlink_metadata},
-    io,
+    collections::{HashMap, HashSet},
+    fs::{self, read_link, symlink_metadata, Metadata},
+    io::{self, Read},
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use walkdir::{DirEntryExt, WalkDir};
+use exclude::{is_cache_dir, PseudoFsDetector};
+use thiserror::Error;
+use walkdir::{DirEntry, WalkDir};
 
 use crate::{
     cmd::GlobalArgs,
-    util::{ContextExt, Hash, MTime},
+    progress::{NoProgress, Progress, ProgressReporter},
+    util::{ContextExt, MTime},
     vault::{
-        backup::{Backup, BackupFile},
+        backup::{Backup, BackupFile, FileId, SkipReason, SpecialFile, XattrValue, Xattrs},
+        storage::{Storage, StoredFile},
         Vault,
     },
 };
 
+/// How many times a file that changes while it is being copied is copied again
+/// before giving up and reporting it.
+const MAX_COPY_RETRIES: usize = 3;
+
+/// Extended attribute values larger than this are deduplicated through storage.
+const MAX_INLINE_XATTR_SIZE: usize = 128;
+
 #[derive(Args)]
 pub struct CliArgs {
     backup_name: String,
-    backup_source_dir: PathBuf,
+
+    /// Directories to back up, as DIR or NAME=DIR. When more than one is given, each is stored
+    /// in a subdirectory of the backup called NAME, or the last component of DIR.
+    #[arg(
+        required_unless_present = "stdin",
+        value_name = "[NAME=]DIR",
+        value_parser = parse_source
+    )]
+    sources: Vec<Source>,
+
+    /// Back up the data read from stdin as a single file, instead of directories
+    #[arg(long, conflicts_with = "sources")]
+    stdin: bool,
+
+    /// Name of the file that the data from stdin is stored as
+    #[arg(long, value_name = "NAME", requires = "stdin", value_parser = parse_name)]
+    stdin_filename: Option<PathBuf>,
+
+    /// Skip entries that can't be read instead of aborting. The skipped entries are recorded
+    /// in the backup, and the exit status is 3.
+    #[arg(long)]
+    continue_on_error: bool,
+
+    /// Back up directories tagged with a CACHEDIR.TAG, which are skipped by default
+    #[arg(long)]
+    include_caches: bool,
+
+    /// Back up pseudo-filesystems like proc, sysfs and devtmpfs, which are skipped by default
+    #[arg(long)]
+    include_pseudo_fs: bool,
+
+    /// Skip files larger than this, eg. "4GiB"
+    #[arg(long, value_name = "SIZE", value_parser = parse_size_arg)]
+    max_file_size: Option<u64>,
+
+    /// Skip files smaller than this, eg. "1KiB"
+    #[arg(long, value_name = "SIZE", value_parser = parse_size_arg)]
+    min_file_size: Option<u64>,
+
+    /// Skip files last modified before this. Either a duration ago, eg. "30days",
+    /// or a time, eg. "2024-01-31" or "2024-01-31 12:00:00" (UTC).
+    #[arg(long, value_name = "TIME", value_parser = parse_time_arg)]
+    newer_than: Option<MTime>,
+
+    /// If a source directory is a symlink, back up the directory it points to. This is the
+    /// default, the flag only undoes an earlier --no-follow-root-symlink.
+    #[arg(long, overrides_with = "no_follow_root_symlink")]
+    follow_root_symlink: bool,
+
+    /// If a source directory is a symlink, store the symlink itself instead of what it
+    /// points to. Not possible for a lone source stored at the root of the backup.
+    #[arg(long, overrides_with = "follow_root_symlink")]
+    no_follow_root_symlink: bool,
+
+    /// Back up what symlinks point to instead of the symlinks themselves.
+    /// Overrides --no-follow-root-symlink. Dangling symlinks are still stored as symlinks.
+    #[arg(long, short = 'L')]
+    dereference: bool,
+}
+
+/// Returned after saving a backup that had to skip some entries because of errors.
+#[derive(Debug, Error)]
+#[error("{0} entries could not be backed up")]
+pub struct IncompleteBackup(usize);
+
+/// Options that control which entries are backed up and how.
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    pub continue_on_error: bool,
+    pub include_caches: bool,
+    pub include_pseudo_fs: bool,
+    pub max_file_size: Option<u64>,
+    pub min_file_size: Option<u64>,
+    pub newer_than: Option<MTime>,
+    /// Store a source directory that is a symlink as a symlink, instead of following it
+    pub store_root_symlink: bool,
+    pub dereference: bool,
 }
 
 pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
-    backup(gargs.vault_dir, &args.backup_name, &args.backup_source_dir)
+    if args.stdin {
+        let filename = args
+            .stdin_filename
+            .unwrap_or_else(|| PathBuf::from("stdin"));
+        backup_stdin(gargs, &args.backup_name, filename)
+    } else {
+        let opts = ScanOptions {
+            continue_on_error: args.continue_on_error,
+            include_caches: args.include_caches,
+            include_pseudo_fs: args.include_pseudo_fs,
+            max_file_size: args.max_file_size,
+            min_file_size: args.min_file_size,
+            newer_than: args.newer_than,
+            store_root_symlink: args.no_follow_root_symlink,
+            dereference: args.dereference,
+        };
+        backup(gargs, &args.backup_name, &args.sources, &opts)
+    }
 }
 
-type NewFile = (PathBuf, Hash);
+#[derive(Clone)]
+struct Source {
+    name: Option<PathBuf>,
+    dir: PathBuf,
+}
 
-fn backup(provided_vault_dir: Option<PathBuf>, bkup_name: &str, bkup_root: &Path) -> Result<()> {
-    let mut vault = Vault::open(provided_vault_dir)?;
+fn parse_source(arg: &str) -> Result<Source, String> {
+    match arg.split_once('=') {
+        Some((name, dir)) => Ok(Source {
+            name: Some(parse_name(name)?),
+            dir: PathBuf::from(dir),
+        }),
+        None => Ok(Source {
+            name: None,
+            dir: PathBuf::from(arg),
+        }),
+    }
+}
+
+/// Parses a name that is used as a single path component at the root of a backup.
+fn parse_name(arg: &str) -> Result<PathBuf, String> {
+    let name = PathBuf::from(arg);
+    if name.components().count() != 1 || name.file_name().is_none() {
+        return Err(format!("{arg:?} is not a valid name"));
+    }
+    Ok(name)
+}
+
+fn parse_size_arg(arg: &str) -> Result<u64, String> {
+    parse_size::parse_size(arg).map_err(|e| e.to_string())
+}
+
+fn parse_time_arg(arg: &str) -> Result<MTime, String> {
+    let time = match humantime::parse_duration(arg) {
+        Ok(ago) => SystemTime::now().checked_sub(ago),
+        Err(_) => {
+            let time = match humantime::parse_rfc3339_weak(arg) {
+                Ok(time) => time,
+                Err(_) => humantime::parse_rfc3339_weak(&format!("{arg} 00:00:00"))
+                    .map_err(|_| format!("{arg:?} is neither a duration nor a time"))?,
+            };
+            Some(time)
+        }
+    };
+    // Times before the epoch can't be represented, and no file is that old
+    time.filter(|&time| time >= SystemTime::UNIX_EPOCH)
+        .map(MTime::from)
+        .ok_or_else(|| format!("{arg:?} is before 1970"))
+}
+
+/// Pairs each source directory with the prefix its contents are stored under.
+/// A lone source without a name is stored at the root of the backup.
+fn source_prefixes(sources: &[Source]) -> Result<Vec<(&Path, PathBuf)>> {
+    if let [Source { name: None, dir }] = sources {
+        return Ok(vec![(dir, PathBuf::new())]);
+    }
+
+    let mut prefixes = Vec::new();
+    for Source { name, dir } in sources {
+        let prefix = match name {
+            Some(name) => name.clone(),
+            None => dir
+                .absolutize()
+                .context_2("absolutize", dir)?
+                .file_name()
+                .map(PathBuf::from)
+                .with_context(|| format!("{}: can't derive a name, use NAME=DIR", dir.display()))?,
+        };
+        prefixes.push((dir.as_path(), prefix));
+    }
+    Ok(prefixes)
+}
+
+/// A file whose contents must be read into storage before it can be added to the backup.
+/// Its hash is computed while it is being copied.
+struct NewFile {
+    path: PathBuf,
+    path_from_root: PathBuf,
+    id: FileId,
+    mtime: MTime,
+    size: u64,
+}
+
+// The path of a new file is either a regular file, or a symlink to one when dereferencing.
+// Following symlinks here gets the metadata of the file that is actually copied in both cases.
+impl NewFile {
+    /// Whether the file on disk no longer matches the size and mtime seen during the scan.
+    fn has_changed(&self) -> Result<bool> {
+        let metadata = fs::metadata(&self.path).path_context(&self.path)?;
+        let (mtime, size) = mtime_and_size(&self.path, &metadata)?;
+        Ok(mtime != self.mtime || size != self.size)
+    }
+
+    /// Re-reads the size and mtime of the file, to be compared against after the next copy.
+    fn restat(&mut self) -> Result<()> {
+        let metadata = fs::metadata(&self.path).path_context(&self.path)?;
+        (self.mtime, self.size) = mtime_and_size(&self.path, &metadata)?;
+        Ok(())
+    }
+}
+
+/// What a backup went through.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub n_files: usize,
+    pub n_new_files: usize,
+    pub n_errors: usize,
+    pub n_cache_dirs: usize,
+    pub n_pseudo_fs: usize,
+    pub n_filtered: usize,
+    pub n_symlink_loops: usize,
+    pub changed_during_backup: Vec<PathBuf>,
+}
+
+impl Summary {
+    fn count_skipped(&mut self, backup: &Backup) {
+        for (_, reason) in backup.iter_skipped() {
+            match reason {
+                SkipReason::Error(_) => self.n_errors += 1,
+                SkipReason::CacheDir => self.n_cache_dirs += 1,
+                SkipReason::PseudoFs => self.n_pseudo_fs += 1,
+                SkipReason::TooLarge { .. }
+                | SkipReason::TooSmall { .. }
+                | SkipReason::TooOld { .. } => self.n_filtered += 1,
+                SkipReason::SymlinkLoop { .. } => self.n_symlink_loops += 1,
+            }
+        }
+    }
+
+    fn print(&self) {
+        println!(
+            "Backed up {} files ({} copied into storage)",
+            self.n_files, self.n_new_files
+        );
+        if self.n_cache_dirs > 0 {
+            println!("Skipped {} cache directories", self.n_cache_dirs);
+        }
+        if self.n_pseudo_fs > 0 {
+            println!("Skipped {} pseudo-filesystems", self.n_pseudo_fs);
+        }
+        if self.n_filtered > 0 {
+            println!("Skipped {} files by size or age", self.n_filtered);
+        }
+        if self.n_symlink_loops > 0 {
+            println!(
+                "Skipped {} symlinks that loop back to a parent directory",
+                self.n_symlink_loops
+            );
+        }
+        if !self.changed_during_backup.is_empty() {
+            println!("Files that changed during the backup, and may be inconsistent:");
+            for path in &self.changed_during_backup {
+                println!("- {}", path.display());
+            }
+        }
+    }
+}
+
+fn backup(
+    gargs: GlobalArgs,
+    bkup_name: &str,
+    sources: &[Source],
+    opts: &ScanOptions,
+) -> Result<()> {
+    let sources = source_prefixes(sources)?;
+    let mut vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut progress = ProgressReporter::new("backup");
+    let summary = backup_into(&mut vault, bkup_name, &sources, opts, &mut progress)?;
+    summary.print();
+    if summary.n_errors > 0 {
+        return Err(IncompleteBackup(summary.n_errors).into());
+    }
+    Ok(())
+}
+
+/// Backs up each source directory under its prefix, as the backup called bkup_name.
+/// If a backup with that name exists, files that are unchanged since then aren't read again.
+pub fn backup_into(
+    vault: &mut Vault,
+    bkup_name: &str,
+    sources: &[(&Path, PathBuf)],
+    opts: &ScanOptions,
+    progress: &mut dyn Progress,
+) -> Result<Summary> {
+    let mut prefixes = HashSet::new();
+    for (_, prefix) in sources {
+        ensure!(
+            prefixes.insert(prefix),
+            "{} is used as a name more than once",
+            prefix.display()
+        );
+    }
     let old_bkup = vault.database.get_backup(bkup_name);
-    let (backup, new_files) = match old_bkup {
-        Some(old_bkup) => update_existing_backup(bkup_root, old_bkup)?,
-        None => new_backup(bkup_root)?,
+    let (mut backup, new_files) = match old_bkup {
+        Some(old_bkup) => {
+            progress.expect(
+                old_bkup.iter_files().len() as u64,
+                old_bkup.total_file_size(),
+            );
+            update_existing_backup(sources, &vault.storage, opts, progress, old_bkup)?
+        }
+        None => new_backup(sources, &vault.storage, opts, progress)?,
     };
-    vault.storage.insert_iter(new_files)?;
+    let mut summary = Summary::default();
+    for mut new_file in new_files {
+        match store_new_file(&vault.storage, &mut new_file, &mut summary, progress) {
+            Ok(stored) => {
+                summary.n_new_files += 1;
+                backup.insert_file(BackupFile::new(
+                    new_file.path_from_root,
+                    new_file.id,
+                    new_file.mtime,
+                    new_file.size,
+                    stored,
+                ));
+            }
+            Err(e) if opts.continue_on_error => {
+                skip_after_error(&mut backup, new_file.path_from_root, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    progress.finish();
+    summary.n_files = backup.iter_files().len();
+    summary.count_skipped(&backup);
+    vault.database.insert_backup(bkup_name, backup);
+    vault.write_database()?;
+    Ok(summary)
+}
+
+/// Copies a new file into storage, copying it again if it changed in the meantime.
+fn store_new_file(
+    storage: &Storage,
+    new_file: &mut NewFile,
+    summary: &mut Summary,
+    progress: &mut dyn Progress,
+) -> Result<StoredFile> {
+    let mut stored = storage
+        .insert_file(&new_file.path, progress)
+        .context_2("inserting file into storage", &new_file.path)?;
+    if new_file.has_changed()? && !recopy_changed_file(storage, new_file, &mut stored, progress)? {
+        summary.changed_during_backup.push(new_file.path.clone());
+    }
+    Ok(stored)
+}
+
+fn skip_after_error(backup: &mut Backup, path_from_root: PathBuf, error: eyre::Report) {
+    eprintln!("[warning] skipping: {error:#}");
+    let reason = SkipReason::Error(format!("{error:#}"));
+    // The other links to a file can't be restored without it
+    for link in backup.remove_hardlinks_to(&path_from_root) {
+        backup.insert_skipped(link, reason.clone());
+    }
+    backup.insert_skipped(path_from_root, reason);
+}
+
+/// Stores everything read from stdin as a backup of a single file.
+fn backup_stdin(gargs: GlobalArgs, bkup_name: &str, name: PathBuf) -> Result<()> {
+    let mut vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut progress = ProgressReporter::new("backup");
+    progress.reading(&name);
+    // The size of stdin isn't known up front, so it is always chunked
+    let mut stdin = CountingReader {
+        inner: io::stdin().lock(),
+        count: 0,
+    };
+    let stored = vault
+        .storage
+        .insert_chunked(&mut stdin, &mut progress)
+        .context("inserting stdin into storage")?;
+    progress.finish();
+
+    let mut backup = Backup::new();
+    let mtime = MTime::from(SystemTime::now());
+    let size = stdin.count;
+    backup.insert_file(BackupFile::new(
+        name,
+        FileId::default(),
+        mtime,
+        size,
+        stored,
+    ));
     vault.database.insert_backup(bkup_name, backup);
-    vault.database.write()?;
+    vault.write_database()?;
+
+    let summary = Summary {
+        n_files: 1,
+        n_new_files: 1,
+        ..Default::default()
+    };
+    summary.print();
     Ok(())
 }
 
-fn new_backup(root: &Path) -> Result<(Backup, Vec<NewFile>)> {
-    scan_dir_into_backup(root, |path, _, _| Ok((Hash::of_file(path)?, true)))
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Copies a file that changed since it was scanned into storage again, until a copy is made
+/// without the file changing underneath it, replacing `stored` with each new copy.
+/// Returns false if the file kept changing.
+///
+/// In that case, the last stored copy is kept, but the file keeps the mtime from before the
+/// copy started, so that the next backup does not trust the stored copy and copies it again.
+fn recopy_changed_file(
+    storage: &Storage,
+    new_file: &mut NewFile,
+    stored: &mut StoredFile,
+    progress: &mut dyn Progress,
+) -> Result<bool> {
+    let scanned_mtime = new_file.mtime;
+    for _ in 0..MAX_COPY_RETRIES {
+        new_file.restat()?;
+        *stored = storage
+            .insert_file(&new_file.path, progress)
+            .context_2("inserting file into storage", &new_file.path)?;
+        if !new_file.has_changed()? {
+            return Ok(true);
+        }
+    }
+    new_file.mtime = scanned_mtime;
+    Ok(false)
+}
+
+fn new_backup(
+    sources: &[(&Path, PathBuf)],
+    storage: &Storage,
+    opts: &ScanOptions,
+    progress: &mut dyn Progress,
+) -> Result<(Backup, Vec<NewFile>)> {
+    scan_into_backup(
+        sources,
+        Scanner::new(storage, opts, progress, |_, _, _| None),
+    )
 }
 
-fn update_existing_backup(root: &Path, old: &Backup) -> Result<(Backup, Vec<NewFile>)> {
-    scan_dir_into_backup(root, |path, ino, mtime| {
-        match old.get_file(ino) {
+fn update_existing_backup(
+    sources: &[(&Path, PathBuf)],
+    storage: &Storage,
+    opts: &ScanOptions,
+    progress: &mut dyn Progress,
+    old: &Backup,
+) -> Result<(Backup, Vec<NewFile>)> {
+    let old_files = old.files_by_id();
+    let scanner = Scanner::new(storage, opts, progress, |id, mtime, size| {
+        let old_file = match old_files.get(&id) {
+            Some(old) => Some(old),
+            // Backups made before the device was recorded only have the inode to go by,
+            // so the size has to match too
+            None => old_files
+                .get(&FileId {
+                    dev: 0,
+                    ino: id.ino,
+                })
+                .filter(|old| old.size == size),
+        };
+        match old_file {
             // A prior file exists with the same inode and a lower mtime.
-            // From, this, we assume that the file has not changed and reuse the old hash.
-            Some(old) if mtime <= old.mtime => Ok((old.hash, false)),
-
-            // A prior file exists with the same inode but a newer mtime.
-            // We need to hash the file to check if it has changed.
-            Some(old) => {
-                let new_hash = Hash::of_file(path)?;
-                if new_hash != old.hash {
-                    Ok((new_hash, true))
-                } else {
-                    Ok((new_hash, false))
-                }
-            }
+            // From, this, we assume that the file has not changed and reuse the old contents.
+            Some(old) if mtime <= old.mtime => Some(old.stored()),
 
-            // This inode was never seen before - we must hash it.
-            // It may be the a file with identical contents of another,
-            // meaning it is technically not "new" as far as storage is concerned.
-            // This leads to a minor amount of excess work in new file insertion.
-            None => Ok((Hash::of_file(path)?, true)),
+            // A prior file exists with the same inode but a newer mtime, or this inode
+            // was never seen before. Either way, the file is copied into storage and hashed
+            // on the way. If its contents turn out to be present already, the copy is dropped.
+            _ => None,
         }
-    })
+    });
+    scan_into_backup(sources, scanner)
 }
 
-fn scan_dir_into_backup<F>(root: &Path, mut file_hook: F) -> Result<(Backup, Vec<NewFile>)>
+fn scan_into_backup<F>(
+    sources: &[(&Path, PathBuf)],
+    mut scanner: Scanner<F>,
+) -> Result<(Backup, Vec<NewFile>)>
 where
-    // (path, inode, mtime) -> result<(file_hash, is_file_new)>
-    F: FnMut(&Path, u64, MTime) -> io::Result<(Hash, bool)>,
+    F: FnMut(FileId, MTime, u64) -> Option<StoredFile>,
 {
-    let mut backup = Backup::new();
-    let mut new_files = Vec::new();
-    for dir_entry in WalkDir::new(root).min_depth(1) {
-        let dir_entry = dir_entry?;
-        let ino = dir_entry.ino();
+    for (dir, prefix) in sources {
+        scanner.scan_dir(dir, prefix)?;
+    }
+    Ok((scanner.backup, scanner.new_files))
+}
+
+/// Walks source directories, collecting everything but the contents of new files into a backup.
+struct Scanner<'a, F> {
+    storage: &'a Storage,
+    opts: &'a ScanOptions,
+    progress: &'a mut dyn Progress,
+    // (device and inode, mtime, size) -> the stored contents of the file if it is known to be
+    // unchanged
+    file_hook: F,
+    backup: Backup,
+    new_files: Vec<NewFile>,
+    // device and inode -> path in the backup, for files with more than one link
+    linked_files: HashMap<FileId, PathBuf>,
+    pseudo_fs: PseudoFsDetector,
+}
+
+impl<'a, F> Scanner<'a, F>
+where
+    F: FnMut(FileId, MTime, u64) -> Option<StoredFile>,
+{
+    fn new(
+        storage: &'a Storage,
+        opts: &'a ScanOptions,
+        progress: &'a mut dyn Progress,
+        file_hook: F,
+    ) -> Self {
+        Self {
+            storage,
+            opts,
+            progress,
+            file_hook,
+            backup: Backup::new(),
+            new_files: Vec::new(),
+            linked_files: HashMap::new(),
+            pseudo_fs: PseudoFsDetector::default(),
+        }
+    }
+
+    /// Adds the contents of root to the backup, under prefix.
+    fn scan_dir(&mut self, root: &Path, prefix: &Path) -> Result<()> {
+        let follow_root = !self.opts.store_root_symlink || self.opts.dereference;
+        let root_metadata = symlink_metadata(root).path_context(root)?;
+        if root_metadata.is_symlink() && !follow_root {
+            ensure!(
+                !prefix.as_os_str().is_empty(),
+                "{} is a symlink, which can't be stored at the root of the backup, give it a \
+                 NAME or drop --no-follow-root-symlink",
+                root.display()
+            );
+            let target = read_link(root).path_context(root)?;
+            self.backup.insert_symlink(target, prefix.to_path_buf());
+            return Ok(());
+        }
+
+        if !prefix.as_os_str().is_empty() {
+            self.backup.insert_directory(prefix.to_path_buf());
+        }
+
+        let mut walk = WalkDir::new(root)
+            .min_depth(1)
+            .follow_root_links(follow_root)
+            .follow_links(self.opts.dereference)
+            .into_iter();
+        while let Some(dir_entry) = walk.next() {
+            let path = match &dir_entry {
+                Ok(dir_entry) => dir_entry.path(),
+                Err(e) => e.path().unwrap_or(root),
+            };
+            let path_from_root = prefix.join(path.strip_prefix(root).unwrap());
+            let is_dir = dir_entry
+                .as_ref()
+                .is_ok_and(|entry| entry.file_type().is_dir());
+            let res = match dir_entry {
+                Ok(dir_entry) => self.scan_entry(dir_entry, path_from_root.clone()),
+                Err(e) => self.scan_walk_error(e, root, prefix, path_from_root.clone()),
+            };
+            match res {
+                Ok(Some(reason)) => {
+                    self.backup.insert_skipped(path_from_root, reason);
+                    walk.skip_current_dir();
+                }
+                Ok(None) => {}
+                Err(e) if self.opts.continue_on_error => {
+                    skip_after_error(&mut self.backup, path_from_root, e);
+                    // Its contents are skipped along with it
+                    if is_dir {
+                        walk.skip_current_dir();
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the errors that come up when following symlinks, which are symlink loops and
+    /// dangling symlinks. Anything else is passed on.
+    fn scan_walk_error(
+        &mut self,
+        error: walkdir::Error,
+        root: &Path,
+        prefix: &Path,
+        path_from_root: PathBuf,
+    ) -> Result<Option<SkipReason>> {
+        if let Some(ancestor) = error.loop_ancestor() {
+            let path = error.path().unwrap_or(ancestor);
+            eprintln!(
+                "[warning] {}: symlink loops back to {}",
+                path.display(),
+                ancestor.display()
+            );
+            let target = prefix.join(ancestor.strip_prefix(root).unwrap());
+            // Nothing was entered, so this must not skip the rest of the parent directory.
+            self.backup
+                .insert_skipped(path_from_root, SkipReason::SymlinkLoop { target });
+            return Ok(None);
+        }
+
+        let dangling_link = error.path().filter(|path| {
+            self.opts.dereference && symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+        });
+        match dangling_link {
+            Some(path) => {
+                let target = read_link(path).path_context(path)?;
+                self.backup.insert_symlink(target, path_from_root);
+                Ok(None)
+            }
+            None => Err(error.into()),
+        }
+    }
+
+    /// Adds a single entry to the backup.
+    /// Returns why, if the entry is a directory that is excluded along with its contents.
+    fn scan_entry(
+        &mut self,
+        dir_entry: DirEntry,
+        path_from_root: PathBuf,
+    ) -> Result<Option<SkipReason>> {
+        // With --dereference, this follows symlinks
+        let metadata = dir_entry.metadata()?;
+        let id = FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        };
+        let followed_symlink = dir_entry.path_is_symlink();
         let path = dir_entry.into_path();
-        let metadata = symlink_metadata(&*path)?;
-        let path_from_root = path.strip_prefix(root).unwrap().to_path_buf();
+        if metadata.is_dir() {
+            if let Some(reason) = self.excluded_dir(&path, &metadata)? {
+                return Ok(Some(reason));
+            }
+        }
+
         if metadata.is_file() {
-            let mtime = MTime::from(metadata.modified().path_context(&path)?);
-            let (hash, is_new) = file_hook(&path, ino, mtime).path_context(&path)?;
-            if is_new {
-                new_files.push((path, hash));
+            let (mtime, size) = mtime_and_size(&path, &metadata)?;
+            if let Some(reason) = self.filtered_file(mtime, size) {
+                self.backup.insert_skipped(path_from_root, reason);
+                return Ok(None);
+            }
+        }
+
+        // A followed symlink is stored as a file of its own, even if its target is also
+        // backed up, and its contents are only stored once because they have the same hash
+        let linked = metadata.is_file() && metadata.nlink() > 1 && !followed_symlink;
+        if linked {
+            if let Some(original) = self.linked_files.get(&id) {
+                self.backup
+                    .insert_hardlink(path_from_root, original.clone());
+                return Ok(None);
+            }
+        }
+
+        if metadata.is_file() || metadata.is_dir() || metadata.is_symlink() {
+            let xattrs =
+                read_xattrs(&path, self.storage, self.opts.dereference).path_context(&path)?;
+            self.backup.insert_xattrs(path_from_root.clone(), xattrs);
+        }
+
+        if metadata.is_file() {
+            let (mtime, size) = mtime_and_size(&path, &metadata)?;
+            // Only once nothing can fail anymore, so that if this path is skipped, the next
+            // link to the file takes its place
+            if linked {
+                self.linked_files.insert(id, path_from_root.clone());
+            }
+            let known = (self.file_hook)(id, mtime, size);
+            self.progress.scanned(&path, size, known.is_some());
+            match known {
+                Some(stored) => self.backup.insert_file(BackupFile::new(
+                    path_from_root,
+                    id,
+                    mtime,
+                    size,
+                    stored,
+                )),
+                None => self.new_files.push(NewFile {
+                    path,
+                    path_from_root,
+                    id,
+                    mtime,
+                    size,
+                }),
             }
-            backup.insert_file(BackupFile {
-                path: path_from_root,
-                ino,
-                hash,
-                mtime,
-            })
         } else if metadata.is_dir() {
-            backup.insert_directory(path_from_root);
+            self.backup.insert_directory(path_from_root);
         } else if metadata.is_symlink() {
             let target = read_link(&*path).path_context(&path)?;
-            backup.insert_symlink(target, path_from_root);
+            self.backup.insert_symlink(target, path_from_root);
+        } else if let Some(special) = special_file(&metadata) {
+            self.backup.insert_special(path_from_root, special);
+        } else if metadata.file_type().is_socket() {
+            // Sockets are created by the program listening on them, and can't be restored.
+            eprintln!("[warning] {}: skipping socket", path.display());
+        } else {
+            bail!("{}: unknown file type", path.display());
+        };
+        Ok(None)
+    }
+
+    fn filtered_file(&self, mtime: MTime, size: u64) -> Option<SkipReason> {
+        let opts = self.opts;
+        if opts.max_file_size.is_some_and(|max| size > max) {
+            Some(SkipReason::TooLarge { size })
+        } else if opts.min_file_size.is_some_and(|min| size < min) {
+            Some(SkipReason::TooSmall { size })
+        } else if opts.newer_than.is_some_and(|cutoff| mtime < cutoff) {
+            Some(SkipReason::TooOld { mtime })
+        } else {
+            None
+        }
+    }
+
+    fn excluded_dir(&mut self, path: &Path, metadata: &Metadata) -> Result<Option<SkipReason>> {
+        if !self.opts.include_pseudo_fs
+            && self
+                .pseudo_fs
+                .is_pseudo_fs(path, metadata.dev())
+                .context_2("statfs", path)?
+        {
+            return Ok(Some(SkipReason::PseudoFs));
+        }
+        if !self.opts.include_caches
+            && is_cache_dir(path).context_2("reading CACHEDIR.TAG", path)?
+        {
+            return Ok(Some(SkipReason::CacheDir));
+        }
+        Ok(None)
+    }
+}
+
+/// Reads all extended attributes of path.
+fn read_xattrs(path: &Path, storage: &Storage, follow_symlinks: bool) -> Result<Xattrs> {
+    let names = if follow_symlinks {
+        xattr::list_deref(path)
+    } else {
+        xattr::list(path)
+    };
+    let names = match names {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Xattrs::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut xattrs = Xattrs::new();
+    for name in names {
+        let value = if follow_symlinks {
+            xattr::get_deref(path, &name)?
+        } else {
+            xattr::get(path, &name)?
+        };
+        let Some(value) = value else {
+            // removed since it was listed
+            continue;
+        };
+        let Ok(name) = name.into_string() else {
+            eprintln!(
+                "[warning] {}: skipping non-UTF-8 xattr name",
+                path.display()
+            );
+            continue;
+        };
+        let value = if value.len() > MAX_INLINE_XATTR_SIZE {
+            XattrValue::Stored(storage.insert_reader(&*value, &mut NoProgress)?)
         } else {
-            bail!("{}: special file", path.display());
+            XattrValue::Inline(value)
+        };
+        xattrs.insert(name, value);
+    }
+    Ok(xattrs)
+}
+
+fn special_file(metadata: &Metadata) -> Option<SpecialFile> {
+    let file_type = metadata.file_type();
+    let rdev = metadata.rdev();
+    let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+    if file_type.is_fifo() {
+        Some(SpecialFile::Fifo)
+    } else if file_type.is_char_device() {
+        Some(SpecialFile::CharDevice { major, minor })
+    } else if file_type.is_block_device() {
+        Some(SpecialFile::BlockDevice { major, minor })
+    } else {
+        None
+    }
+}
+
+fn mtime_and_size(path: &Path, metadata: &Metadata) -> Result<(MTime, u64)> {
+    let mtime = MTime::from(metadata.modified().path_context(path)?);
+    Ok((mtime, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> MTime {
+        MTime::from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn time_ago() {
+        let before = MTime::from(SystemTime::now() - Duration::from_secs(2 * 3600));
+        let time = parse_time_arg("1h 30min").unwrap();
+        let after = MTime::from(SystemTime::now() - Duration::from_secs(3600));
+        assert!(before < time && time < after);
+    }
+
+    #[test]
+    fn absolute_time() {
+        assert_eq!(parse_time_arg("2024-01-31").unwrap(), at(1706659200));
+        assert_eq!(
+            parse_time_arg("2024-01-31 12:00:00").unwrap(),
+            at(1706702400)
+        );
+        assert_eq!(parse_time_arg("1970-01-01").unwrap(), at(0));
+    }
+
+    #[test]
+    fn bad_time() {
+        let e = parse_time_arg("last tuesday").unwrap_err();
+        assert_eq!(e, r#""last tuesday" is neither a duration nor a time"#);
+        assert!(parse_time_arg("2024-13-01").is_err());
+        assert!(parse_time_arg("").is_err());
+        assert!(parse_time_arg("1969-12-31").is_err());
+        assert!(parse_time_arg("100years")
+            .unwrap_err()
+            .ends_with("is before 1970"));
+    }
+
+    #[test]
+    fn source_names() {
+        let sources = |args: &[&str]| -> Vec<Source> {
+            args.iter().map(|arg| parse_source(arg).unwrap()).collect()
         };
+        let single = sources(&["some/dir"]);
+        assert_eq!(
+            source_prefixes(&single).unwrap(),
+            [(Path::new("some/dir"), PathBuf::new())]
+        );
+
+        let several = sources(&["home=/home/me", "/etc/", "src"]);
+        let prefixes: Vec<_> = source_prefixes(&several)
+            .unwrap()
+            .into_iter()
+            .map(|(_, prefix)| prefix)
+            .collect();
+        assert_eq!(prefixes, ["home", "etc", "src"].map(PathBuf::from));
+
+        assert!(source_prefixes(&sources(&["/", "src"])).is_err());
+        assert!(parse_source("a/b=dir").is_err());
+        assert!(parse_source("..=dir").is_err());
     }
-    Ok((backup, new_files))
 }
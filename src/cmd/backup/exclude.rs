@@ -0,0 +1,107 @@
+// statfs's f_type and the libc magic numbers have different types on different platforms,
+// so they are all cast to i64, which is a no-op on some.
+#![allow(clippy::unnecessary_cast)]
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// The start of a valid CACHEDIR.TAG, as specified at https://bford.info/cachedir/
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Filesystems whose contents are made up by the kernel, and are never worth backing up.
+const PSEUDO_FS_TYPES: &[i64] = &[
+    libc::PROC_SUPER_MAGIC as i64,
+    libc::SYSFS_MAGIC as i64,
+    libc::CGROUP_SUPER_MAGIC as i64,
+    libc::CGROUP2_SUPER_MAGIC as i64,
+    libc::DEVPTS_SUPER_MAGIC as i64,
+    libc::DEBUGFS_MAGIC as i64,
+    libc::TRACEFS_MAGIC as i64,
+    libc::SECURITYFS_MAGIC as i64,
+    libc::SELINUX_MAGIC as i64,
+    libc::SMACK_MAGIC as i64,
+    libc::BPF_FS_MAGIC as i64,
+    libc::NSFS_MAGIC as i64,
+    libc::RDTGROUP_SUPER_MAGIC as i64,
+    libc::USBDEVICE_SUPER_MAGIC as i64,
+    BINFMTFS_MAGIC,
+    CONFIGFS_MAGIC,
+    EFIVARFS_MAGIC,
+    FUSECTL_SUPER_MAGIC,
+    MQUEUE_MAGIC,
+    PSTOREFS_MAGIC,
+];
+
+// from linux/magic.h, missing from libc
+const BINFMTFS_MAGIC: i64 = 0x42494e4d;
+const CONFIGFS_MAGIC: i64 = 0x62656570;
+const EFIVARFS_MAGIC: i64 = 0xde5e81e4;
+const FUSECTL_SUPER_MAGIC: i64 = 0x65735543;
+const MQUEUE_MAGIC: i64 = 0x19800202;
+const PSTOREFS_MAGIC: i64 = 0x6165676c;
+
+/// Whether dir holds a CACHEDIR.TAG with a valid signature.
+pub fn is_cache_dir(dir: &Path) -> io::Result<bool> {
+    let mut tag = match File::open(dir.join("CACHEDIR.TAG")) {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut buf = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    match tag.read_exact(&mut buf) {
+        Ok(()) => Ok(buf == CACHEDIR_TAG_SIGNATURE),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Detects directories on pseudo-filesystems. The filesystem type is looked up once per device.
+#[derive(Default)]
+pub struct PseudoFsDetector(HashMap<u64, bool>);
+
+impl PseudoFsDetector {
+    pub fn is_pseudo_fs(&mut self, dir: &Path, dev: u64) -> io::Result<bool> {
+        if let Some(&is_pseudo) = self.0.get(&dev) {
+            return Ok(is_pseudo);
+        }
+        let fs_type = statfs_type(dir)?;
+        let is_pseudo = PSEUDO_FS_TYPES.contains(&fs_type)
+            || (fs_type == libc::TMPFS_MAGIC as i64 && is_devtmpfs(dev));
+        self.0.insert(dev, is_pseudo);
+        Ok(is_pseudo)
+    }
+}
+
+fn statfs_type(path: &Path) -> io::Result<i64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: path is a valid nul-terminated string, and buf has room for a struct statfs
+    if unsafe { libc::statfs(path.as_ptr(), buf.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statfs succeeded, so it filled in buf
+    let buf = unsafe { buf.assume_init() };
+    Ok(buf.f_type as i64)
+}
+
+/// devtmpfs has the same statfs type as tmpfs, so mountinfo is checked to tell them apart.
+fn is_devtmpfs(dev: u64) -> bool {
+    let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+        return false;
+    };
+    let dev_id = format!("{}:{}", libc::major(dev), libc::minor(dev));
+    mountinfo.lines().any(|line| {
+        // 36 35 0:5 / /dev rw,nosuid shared:2 - devtmpfs udev rw,size=8080588k
+        line.split(' ').nth(2) == Some(&dev_id)
+            && line
+                .split_once(" - ")
+                .is_some_and(|(_, fs)| fs.starts_with("devtmpfs "))
+    })
+}
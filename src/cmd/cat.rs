@@ -0,0 +1,38 @@
+use clap::Args;
+use eyre::{Context, ContextCompat, Result};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::vault::Vault;
+
+use super::GlobalArgs;
+
+#[derive(Args)]
+pub struct CliArgs {
+    backup_name: String,
+    /// Path of the file inside the backup
+    path: PathBuf,
+}
+
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    cat(gargs, &args.backup_name, &args.path)
+}
+
+/// Writes the contents of a backed up file to stdout.
+fn cat(gargs: GlobalArgs, backup: &str, path: &Path) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let bkup = vault
+        .database
+        .get_backup(backup)
+        .with_context(|| format!("backup {backup:?} does not exist"))?;
+    let file = bkup
+        .find_file(path)
+        .with_context(|| format!("{} is not a file in {backup:?}", path.display()))?;
+
+    let mut src = vault.storage.open_blobs(file.blobs());
+    let mut stdout = io::stdout().lock();
+    io::copy(&mut src, &mut stdout).context("writing to stdout")?;
+    stdout.flush().context("writing to stdout")
+}
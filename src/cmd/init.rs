@@ -1,22 +1,112 @@
-use std::fs::create_dir;
+use std::fs;
 
 use clap::Args;
-use eyre::Result;
+use eyre::{ensure, Result};
+use std::path::PathBuf;
 
 use super::GlobalArgs;
 use crate::{
-    util::{ensure_dir_exists_and_is_empty, path_or_cwd},
-    vault::database::Database,
+    util::{ensure_dir_exists_and_is_empty, path_or_cwd, ContextExt},
+    vault::{
+        self,
+        backend::{Backend, LocalBackend, MirrorBackend, S3Backend, S3Config},
+        config::Config,
+        crypto::{self, Cipher, KeySource},
+        Vault,
+    },
 };
 
 #[derive(Args)]
-pub struct CliArgs {}
+pub struct CliArgs {
+    /// Compress stored files with zstd at this level (1-22).
+    /// Files that don't get smaller are stored uncompressed.
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: Option<i32>,
+
+    /// Encrypt the vault, with the key file given by --key-file if there is one,
+    /// otherwise with a passphrase
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Store Reed–Solomon parity for stored files, adding this percentage of their size (1-100),
+    /// so that verify and repair can rebuild files damaged by bitrot
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    parity: Option<u8>,
+
+    /// Keep a copy of every stored object in this directory too, usually on another disk.
+    /// It must exist and be empty. May be given multiple times.
+    #[arg(long, value_name = "DIR")]
+    mirror: Vec<PathBuf>,
+
+    /// Make stored files immutable, like `chattr +i`, when running as root. They are always
+    /// read-only, but root can write to them anyway.
+    #[arg(long)]
+    immutable: bool,
+}
 
 // TODO: Move this logic into vault module?
 // TODO: mkdir for user
-pub fn run(gargs: GlobalArgs, _args: CliArgs) -> Result<()> {
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
     let vault_dir = &path_or_cwd(gargs.vault_dir);
-    ensure_dir_exists_and_is_empty(vault_dir)?;
-    create_dir(vault_dir.join("data"))?;
-    Database::new(vault_dir).write()
+    let mut mirrors = Vec::new();
+    let backend: Box<dyn Backend> = match vault::s3_url(vault_dir) {
+        Some(url) => {
+            ensure!(
+                args.mirror.is_empty(),
+                "mirrors are only supported for vaults in a directory"
+            );
+            let backend = S3Backend::new(S3Config::from_url(url)?)?;
+            ensure!(backend.list("")?.is_empty(), "{url} is not empty");
+            Box::new(backend)
+        }
+        None => {
+            // Everything is checked before anything is created, so that a bad mirror
+            // doesn't leave the others half initialized
+            let mut dirs: Vec<PathBuf> = Vec::new();
+            for dir in std::iter::once(vault_dir).chain(&args.mirror) {
+                ensure_dir_exists_and_is_empty(dir)?;
+                let canonical = fs::canonicalize(dir).context_2("canonicalize", dir)?;
+                ensure!(
+                    !dirs.contains(&canonical),
+                    "{} is given more than once, as the vault or a mirror",
+                    dir.display()
+                );
+                dirs.push(canonical);
+            }
+            let mut replicas: Vec<Box<dyn Backend>> = Vec::new();
+            for dir in &dirs {
+                let data = dir.join("data");
+                fs::create_dir(&data).context_2("create_dir", &data)?;
+                let backend = LocalBackend::new(dir).with_immutable(args.immutable);
+                replicas.push(Box::new(backend));
+            }
+            mirrors = dirs.split_off(1);
+            match replicas.len() {
+                1 => replicas.pop().unwrap(),
+                _ => Box::new(MirrorBackend::new(replicas)),
+            }
+        }
+    };
+
+    let cipher = args.encrypt.then(Cipher::generate);
+    let encryption = match &cipher {
+        Some(cipher) => {
+            let source = match gargs.key_file {
+                Some(_) => KeySource::KeyFile,
+                None => KeySource::Passphrase,
+            };
+            let secret = crypto::read_secret(source, gargs.key_file.as_deref(), true)?;
+            Some(cipher.wrap(source, &secret)?)
+        }
+        None => None,
+    };
+    let config = Config {
+        compression_level: args.compression_level,
+        encryption,
+        parity: args.parity,
+        mirrors,
+        immutable: args.immutable,
+    };
+    Vault::create(backend, config, cipher)?;
+    Ok(())
 }
@@ -1,6 +1,5 @@
 use clap::Args;
 use eyre::Result;
-use std::path::PathBuf;
 
 use super::GlobalArgs;
 use crate::vault::Vault;
@@ -15,15 +14,11 @@ pub struct CliArgs {
 }
 
 pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
-    list(gargs.vault_dir, args.backup_name, args.full)
+    list(gargs, args.backup_name, args.full)
 }
 
-fn list(
-    provided_vault_dir: Option<PathBuf>,
-    backup_name: Option<String>,
-    full: bool,
-) -> Result<()> {
-    let vault = Vault::open(provided_vault_dir)?;
+fn list(gargs: GlobalArgs, backup_name: Option<String>, full: bool) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
 
     match backup_name {
         Some(name) => list_backup(&vault, &name, full),
@@ -46,6 +41,9 @@ fn list_all_backups(vault: &Vault, full: bool) {
             let n_files = bkup.iter_files().len();
             let n_dirs = bkup.iter_directories().len();
             let n_links = bkup.iter_symlinks().len();
+            let n_specials = bkup.iter_specials().len();
+            let n_hardlinks = bkup.iter_hardlinks().len();
+            let n_skipped = bkup.iter_skipped().len();
             if n_files > 0 {
                 println!("  files:       {n_files}");
             }
@@ -55,6 +53,15 @@ fn list_all_backups(vault: &Vault, full: bool) {
             if n_links > 0 {
                 println!("  symlinks:    {n_links}");
             }
+            if n_specials > 0 {
+                println!("  special:     {n_specials}");
+            }
+            if n_hardlinks > 0 {
+                println!("  hardlinks:   {n_hardlinks}");
+            }
+            if n_skipped > 0 {
+                println!("  skipped:     {n_skipped}");
+            }
         } else {
             todo!()
         }
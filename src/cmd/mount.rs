@@ -3,7 +3,8 @@ use eyre::{Context, ContextCompat, Result};
 use path_absolutize::Absolutize;
 use std::{
     env::current_dir,
-    fs::create_dir_all,
+    fs::{create_dir_all, File},
+    io,
     os::unix::fs::symlink,
     path::{Path, PathBuf},
 };
@@ -22,12 +23,12 @@ pub struct CliArgs {
 }
 
 pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
-    mount(gargs.vault_dir, &args.mount_point, &args.backup_name)
+    mount(gargs, &args.mount_point, &args.backup_name)
 }
 
-fn mount(vault_dir: Option<PathBuf>, mount_point: &Path, backup: &str) -> Result<()> {
+fn mount(gargs: GlobalArgs, mount_point: &Path, backup: &str) -> Result<()> {
     ensure_dir_exists_and_is_empty(mount_point)?;
-    let vault = Vault::open(vault_dir)?;
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
     let bkup = vault
         .database
         .get_backup(backup)
@@ -48,7 +49,18 @@ fn mount(vault_dir: Option<PathBuf>, mount_point: &Path, backup: &str) -> Result
             .absolutize_from(&cwd)
             .context_2("absolutize", &file_dest)?;
 
-        let file_source = vault.storage.path_of(file.hash);
+        // compressed, encrypted, packed or chunked files can't be linked to, so they are copied
+        let raw_path = match file.blobs() {
+            [hash] => vault.storage.raw_path_of(*hash),
+            _ => None,
+        };
+        let Some(file_source) = raw_path else {
+            let mut src = vault.storage.open_blobs(file.blobs());
+            File::create(&file_dest)
+                .and_then(|mut dest| io::copy(&mut src, &mut dest))
+                .context_2("decompressing into", &file_dest)?;
+            continue;
+        };
         let file_source = file_source
             .absolutize_from(&cwd)
             .context_2("absolutize", &file_source)?;
@@ -63,6 +75,28 @@ fn mount(vault_dir: Option<PathBuf>, mount_point: &Path, backup: &str) -> Result
         })?;
     }
 
+    // hardlinks become symlinks to the mounted file they are linked to
+    for (path, original) in bkup.iter_hardlinks() {
+        let link_dest = mount_point.join(path);
+        let link_dest = link_dest
+            .absolutize_from(&cwd)
+            .context_2("absolutize", &link_dest)?;
+
+        let original = mount_point.join(original);
+        let original = original
+            .absolutize_from(&cwd)
+            .context_2("absolutize", &original)?;
+        let original = pathdiff::diff_paths(original, link_dest.parent().unwrap()).unwrap();
+
+        symlink(&original, &link_dest).with_context(|| {
+            format!(
+                "symlinking {} -> {}",
+                original.display(),
+                link_dest.display()
+            )
+        })?;
+    }
+
     // create the backed-up symlinks in the directories
     for (link_name, target) in bkup.iter_symlinks() {
         let link_dest = mount_point.join(link_name);
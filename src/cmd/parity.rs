@@ -0,0 +1,39 @@
+use clap::Args;
+use eyre::{bail, Result};
+
+use crate::{
+    progress::ProgressReporter,
+    vault::{config::Config, Vault},
+};
+
+use super::GlobalArgs;
+
+/// Stores parity for the blobs and packs that have none
+///
+/// Parity of blobs and packs that are missing is kept, so that repair can rebuild them.
+#[derive(Args)]
+pub struct CliArgs {
+    /// Parity to store, as a percentage of the size of each stored file (1-100). It is saved
+    /// in the vault config, so files stored later get the same. Defaults to the saved setting.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    percent: Option<u8>,
+}
+
+/// Stores parity for the blobs and packs that have none yet, turning parity on for the vault.
+/// Run verify first, since parity of a corrupted blob can only rebuild it corrupted.
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut config = Config::load(vault.storage.backend())?;
+    let Some(percent) = args.percent.or(config.parity) else {
+        bail!("this vault has no parity, turn it on with --percent");
+    };
+    if config.parity != Some(percent) {
+        config.parity = Some(percent);
+        config.write(vault.storage.backend())?;
+    }
+
+    let mut progress = ProgressReporter::new("parity");
+    let added = vault.storage.add_parity(percent, &mut progress)?;
+    println!("Stored parity for {added} objects");
+    Ok(())
+}
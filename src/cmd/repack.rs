@@ -0,0 +1,28 @@
+use clap::Args;
+use eyre::Result;
+use std::collections::HashSet;
+
+use crate::{progress::format_bytes, util::Hash, vault::Vault};
+
+use super::GlobalArgs;
+
+#[derive(Args)]
+pub struct CliArgs {}
+
+/// Rewrites the packs that hold blobs no backup refers to anymore, without those blobs.
+pub fn run(gargs: GlobalArgs, _args: CliArgs) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let live: HashSet<Hash> = vault
+        .database
+        .iter_backups()
+        .flat_map(|(_, bkup)| bkup.iter_blobs())
+        .collect();
+    let stats = vault.storage.repack(&live)?;
+    println!(
+        "Rewrote {} packs, dropping {} unused blobs ({})",
+        stats.packs_rewritten,
+        stats.blobs_dropped,
+        format_bytes(stats.bytes_freed)
+    );
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use clap::Args;
+use eyre::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    progress::{NoProgress, Progress, ProgressReporter},
+    util::Hash,
+    vault::{self, Vault},
+};
+
+use super::{
+    verify::{find_damage, print_damage, DamagedCopy},
+    GlobalArgs,
+};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Where to look for good copies of damaged blobs that parity can't rebuild: a directory
+    /// with the original files, or another copy of this vault. May be given multiple times.
+    #[arg(long, value_name = "DIR|VAULT")]
+    from: Vec<PathBuf>,
+
+    /// Key file that unlocks the vaults given with --from, if they are encrypted
+    #[arg(long, value_name = "KEY_FILE")]
+    from_key_file: Option<PathBuf>,
+}
+
+/// Replaces corrupted and missing blobs with copies whose contents match their hash, rebuilt
+/// from parity or found among original files or in another vault. Corrupted copies are moved
+/// to `quarantine/`. In a mirrored vault, a copy that is damaged while another one is intact
+/// is rewritten from the intact one, leaving the other copies alone. Packs whose index can't
+/// be read are moved to `quarantine/` once every blob is repaired.
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut progress = ProgressReporter::new("verify");
+    let mut damage = find_damage(&vault, None, &mut progress)?;
+    if damage.is_empty() {
+        println!("No damaged blobs found");
+        return Ok(());
+    }
+
+    let n_copies = damage.damaged_copies.len();
+    let mut rewritten = HashSet::new();
+    damage.damaged_copies.retain(|damaged| {
+        let DamagedCopy {
+            hash, copy, intact, ..
+        } = *damaged;
+        match vault
+            .storage
+            .replace_copy(hash, copy, intact, &mut rewritten)
+        {
+            Ok(()) => false,
+            Err(e) => {
+                eprintln!("[warning] {e:#}");
+                true
+            }
+        }
+    });
+    if n_copies > 0 {
+        println!(
+            "Rewrote {} of {n_copies} damaged copies from intact ones",
+            n_copies - damage.damaged_copies.len()
+        );
+    }
+
+    let n_damaged = damage.corrupted.len() + damage.missing.len();
+    let mut damaged: HashSet<Hash> = damage
+        .corrupted
+        .iter()
+        .chain(&damage.missing)
+        .copied()
+        .collect();
+    repair_from_parity(&vault, &mut damaged);
+    for source in &args.from {
+        if damaged.is_empty() {
+            break;
+        }
+        if is_vault(source) {
+            let other = Vault::open(Some(source.clone()), args.from_key_file.as_deref())
+                .with_context(|| format!("opening {}", source.display()))?;
+            repair_from_vault(&vault, &other, &mut damaged)?;
+        } else {
+            let mut progress = ProgressReporter::new("repair");
+            repair_from_dir(&vault, source, &mut damaged, &mut progress)?;
+        }
+    }
+
+    println!(
+        "Repaired {} of {} damaged blobs",
+        n_damaged - damaged.len(),
+        n_damaged
+    );
+    if damaged.is_empty() && damage.damaged_copies.is_empty() {
+        // The blobs backups need from packs whose index can't be read are stored again
+        if !damage.unreadable_indexes.is_empty() {
+            vault.storage.quarantine_unreadable_packs()?;
+            println!(
+                "Moved {} packs with unreadable indexes to quarantine",
+                damage.unreadable_indexes.len()
+            );
+        }
+        return Ok(());
+    }
+    damage.corrupted.retain(|hash| damaged.contains(hash));
+    damage.missing.retain(|hash| damaged.contains(hash));
+    print_damage(&vault, &damage);
+    bail!(
+        "{} blobs and {} damaged copies could not be repaired",
+        damaged.len(),
+        damage.damaged_copies.len()
+    )
+}
+
+/// Rebuilds the objects holding damaged blobs from their parity, if the vault has any.
+fn repair_from_parity(vault: &Vault, damaged: &mut HashSet<Hash>) {
+    let mut repaired = Vec::new();
+    for &hash in damaged.iter() {
+        if let Err(e) = vault.storage.repair_from_parity(hash, false) {
+            eprintln!("[warning] {e:#}");
+            continue;
+        }
+        // An object holding several damaged blobs is rebuilt for the first of them
+        if let Ok(actual) = vault.storage.rehash_blob(hash, &mut NoProgress) {
+            if actual == hash {
+                repaired.push(hash);
+            }
+        }
+    }
+    for hash in repaired {
+        damaged.remove(&hash);
+    }
+}
+
+/// Vaults have a database, and older ones no config file.
+fn is_vault(path: &Path) -> bool {
+    vault::s3_url(path).is_some()
+        || path.join("config.json").is_file()
+        || path.join("database.json").is_file()
+}
+
+/// Copies damaged blobs from another vault. Since blobs in encrypted vaults are named by a
+/// keyed hash, this only finds anything in copies of the same vault.
+fn repair_from_vault(vault: &Vault, other: &Vault, damaged: &mut HashSet<Hash>) -> Result<()> {
+    let mut repaired = Vec::new();
+    for &hash in damaged.iter() {
+        if !other.storage.contains(hash)? {
+            continue;
+        }
+        let data = match other.storage.read_blob(hash) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("[warning] {e:#}");
+                continue;
+            }
+        };
+        match vault.storage.replace_blob(hash, &data) {
+            Ok(()) => repaired.push(hash),
+            // The other copy is damaged too
+            Err(e) => eprintln!("[warning] {e:#}"),
+        }
+    }
+    for hash in repaired {
+        damaged.remove(&hash);
+    }
+    Ok(())
+}
+
+/// Rehashes every file under dir, split into blobs like a backup would, and replaces the
+/// damaged blobs that turn up.
+fn repair_from_dir(
+    vault: &Vault,
+    dir: &Path,
+    damaged: &mut HashSet<Hash>,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    let mut repaired = Vec::new();
+    for entry in WalkDir::new(dir) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("[warning] {e}");
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        progress.reading(entry.path());
+        let res = vault.storage.split_file(entry.path(), |hash, data| {
+            progress.hashed(data.len() as u64);
+            if damaged.contains(&hash) && !repaired.contains(&hash) {
+                vault.storage.replace_blob(hash, data)?;
+                repaired.push(hash);
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            eprintln!("[warning] {e:#}");
+        }
+        if repaired.len() == damaged.len() {
+            break;
+        }
+    }
+    progress.finish();
+    for hash in repaired {
+        damaged.remove(&hash);
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use clap::Args;
+use eyre::{Context, ContextCompat, Result};
+use std::{
+    ffi::CString,
+    fs::{self, create_dir_all, File},
+    io,
+    os::unix::{ffi::OsStrExt, fs::symlink},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    progress::{self, Progress, ProgressReporter},
+    util::{ensure_dir_exists_and_is_empty, ContextExt},
+    vault::{
+        backup::{SpecialFile, XattrValue},
+        Vault,
+    },
+};
+
+use super::GlobalArgs;
+
+#[derive(Args)]
+pub struct CliArgs {
+    backup_name: String,
+    destination: PathBuf,
+
+    /// Don't restore extended attributes in this namespace, eg. "security" or "trusted",
+    /// which need privileges to set. May be given multiple times.
+    #[arg(long = "skip-xattr-namespace", value_name = "NAMESPACE")]
+    skip_xattr_namespaces: Vec<String>,
+}
+
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    restore(
+        gargs,
+        &args.destination,
+        &args.backup_name,
+        &args.skip_xattr_namespaces,
+    )
+}
+
+fn restore(
+    gargs: GlobalArgs,
+    dest: &Path,
+    backup: &str,
+    skip_xattr_namespaces: &[String],
+) -> Result<()> {
+    ensure_dir_exists_and_is_empty(dest)?;
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut progress = ProgressReporter::new("restore");
+    restore_from(&vault, backup, dest, skip_xattr_namespaces, &mut progress)
+}
+
+/// Restores the backup called `backup` into dest, which should be empty.
+pub fn restore_from(
+    vault: &Vault,
+    backup: &str,
+    dest: &Path,
+    skip_xattr_namespaces: &[String],
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    let bkup = vault
+        .database
+        .get_backup(backup)
+        .with_context(|| format!("backup {backup:?} does not exist"))?;
+
+    for dir in bkup.iter_directories() {
+        let dir_dest = dest.join(dir);
+        create_dir_all(&dir_dest).context_2("mkdir", dir_dest)?;
+    }
+
+    progress.expect(bkup.iter_files().len() as u64, bkup.total_file_size());
+    for file in bkup.iter_files() {
+        let file_dest = dest.join(&file.path);
+        progress.reading(&file.path);
+        let src = vault.storage.open_blobs(file.blobs());
+        File::create(&file_dest)
+            .and_then(|dest| {
+                progress::copy(src, &dest, progress)?;
+                dest.set_modified(file.mtime.into())
+            })
+            .with_context(|| format!("restoring {}", file_dest.display()))?;
+    }
+    progress.finish();
+
+    for (path, original) in bkup.iter_hardlinks() {
+        let link_dest = dest.join(path);
+        let original = dest.join(original);
+        fs::hard_link(&original, &link_dest).with_context(|| {
+            format!(
+                "hardlinking {} -> {}",
+                link_dest.display(),
+                original.display()
+            )
+        })?;
+    }
+
+    for (link_name, target) in bkup.iter_symlinks() {
+        let link_dest = dest.join(link_name);
+        symlink(target, &link_dest).with_context(|| {
+            format!("symlinking {} -> {}", link_dest.display(), target.display())
+        })?;
+    }
+
+    for (path, special) in bkup.iter_specials() {
+        let special_dest = dest.join(path);
+        match make_special_file(&special_dest, *special) {
+            // Device nodes can only be created by root.
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("[warning] {}: {e}", special_dest.display());
+            }
+            res => res.context_2("mknod", &special_dest)?,
+        }
+    }
+
+    // Extended attributes go last, so that ACLs on directories can't get in the way
+    // of creating their contents.
+    for (path, xattrs) in bkup.iter_xattrs() {
+        let xattr_dest = dest.join(path);
+        for (name, value) in xattrs {
+            let namespace = name.split('.').next().unwrap_or_default();
+            if skip_xattr_namespaces.iter().any(|ns| ns == namespace) {
+                continue;
+            }
+            let value = match value {
+                XattrValue::Inline(value) => value.clone(),
+                XattrValue::Stored(hash) => vault.storage.read_blob(*hash)?,
+            };
+            xattr::set(&xattr_dest, name, &value)
+                .with_context(|| format!("setting xattr {name} ({})", xattr_dest.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn make_special_file(path: &Path, special: SpecialFile) -> io::Result<()> {
+    let (kind, dev) = match special {
+        SpecialFile::Fifo => (libc::S_IFIFO, 0),
+        SpecialFile::CharDevice { major, minor } => (libc::S_IFCHR, libc::makedev(major, minor)),
+        SpecialFile::BlockDevice { major, minor } => (libc::S_IFBLK, libc::makedev(major, minor)),
+    };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: path is a valid nul-terminated string
+    let ret = unsafe { libc::mknod(path.as_ptr(), kind | 0o666, dev) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
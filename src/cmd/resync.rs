@@ -0,0 +1,109 @@
+use clap::Args;
+use eyre::{bail, ensure, Result};
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use crate::{
+    progress::ProgressReporter,
+    util::{ensure_dir_exists_and_is_empty, ContextExt, Hash},
+    vault::{self, backend::resync, config::Config, Vault},
+};
+
+use super::GlobalArgs;
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Add this directory as a mirror of the vault first. It must be empty, unless it already
+    /// holds a copy of the vault. May be given multiple times.
+    #[arg(long, value_name = "DIR")]
+    add: Vec<PathBuf>,
+
+    /// Also rehash every blob in each copy, and replace damaged copies with intact ones
+    #[arg(long)]
+    verify: bool,
+}
+
+/// Brings every mirror up to date with the vault, like after a disk was replaced or was
+/// offline during a backup. Objects the vault itself lost are first copied back from a mirror.
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    let vault_dir = vault::resolve_dir(gargs.vault_dir);
+    let mut new_mirrors = Vec::new();
+    if !args.add.is_empty() {
+        ensure!(
+            vault::s3_url(&vault_dir).is_none(),
+            "mirrors are only supported for vaults in a directory"
+        );
+        let vault = Vault::open(Some(vault_dir.clone()), gargs.key_file.as_deref())?;
+        let mut config = Config::load(vault.storage.backend())?;
+        let canonical_vault = fs::canonicalize(&vault_dir).context_2("canonicalize", &vault_dir)?;
+        for dir in &args.add {
+            let canonical = fs::canonicalize(dir).context_2("canonicalize", dir)?;
+            ensure!(
+                canonical != canonical_vault,
+                "{} is the vault itself",
+                dir.display()
+            );
+            // Resync deletes what the vault doesn't have, so only an empty directory or a copy
+            // of this vault can become a mirror
+            if !vault::is_mirror_of(&config, &canonical)? {
+                ensure_dir_exists_and_is_empty(dir)?;
+            }
+            let existing = config
+                .mirrors
+                .iter()
+                .find(|mirror| fs::canonicalize(mirror).is_ok_and(|mirror| mirror == canonical));
+            match existing {
+                Some(mirror) => new_mirrors.push(mirror.clone()),
+                None => {
+                    config.mirrors.push(canonical.clone());
+                    new_mirrors.push(canonical);
+                }
+            }
+        }
+        config.write(vault.storage.backend())?;
+    }
+
+    let vault = Vault::open_all_mirrors(
+        Some(vault_dir.clone()),
+        gargs.key_file.as_deref(),
+        &new_mirrors,
+    )?;
+    let replicas = vault.storage.backend().replicas();
+    ensure!(
+        !replicas.is_empty(),
+        "this vault has no mirrors, add one with --add"
+    );
+    let live: HashSet<Hash> = vault
+        .database
+        .iter_backups()
+        .flat_map(|(_, bkup)| bkup.iter_blobs())
+        .collect();
+    let mut progress = ProgressReporter::new("restore");
+    let restored = vault.storage.restore_primary(&live, &mut progress)?;
+    if restored > 0 {
+        println!("Restored {restored} objects the vault lost from its mirrors");
+    }
+    let mut progress = ProgressReporter::new("resync");
+    let (copied, deleted) = resync(&replicas, &mut progress)?;
+    println!("Copied {copied} objects to mirrors, and deleted {deleted} the vault no longer has");
+    if !args.verify {
+        return Ok(());
+    }
+
+    // The pack index was loaded from every copy, including packs that were just deleted
+    drop(vault);
+    let vault = Vault::open_all_mirrors(Some(vault_dir), gargs.key_file.as_deref(), &new_mirrors)?;
+    let mut progress = ProgressReporter::new("verify");
+    let (replaced, lost) = vault.storage.verify_replicas(&mut progress)?;
+    println!("Replaced {replaced} damaged objects");
+    if lost.is_empty() {
+        return Ok(());
+    }
+    println!("Damaged in every copy:");
+    for hash in &lost {
+        println!("- {hash}");
+    }
+    bail!(
+        "{} blobs are damaged in every copy, try the repair command",
+        lost.len()
+    )
+}
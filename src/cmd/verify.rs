@@ -0,0 +1,242 @@
+use clap::Args;
+use eyre::{bail, Result};
+use rand::seq::SliceRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{
+    progress::{Progress, ProgressReporter},
+    util::Hash,
+    vault::Vault,
+};
+
+use super::GlobalArgs;
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Only rehash this percentage of the stored blobs, picked at random.
+    /// Missing blobs are still looked for in every backup.
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    sample: Option<f64>,
+}
+
+fn parse_percent(arg: &str) -> Result<f64, String> {
+    match arg.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err("expected a percentage between 0 and 100".to_owned()),
+    }
+}
+
+/// Blobs that can't be used to restore the files that refer to them.
+#[derive(Debug, Default)]
+pub(super) struct Damage {
+    /// How many blobs were rehashed, out of how many are stored
+    pub checked: usize,
+    pub stored: usize,
+    /// Blobs whose contents don't match their hash, or that can't be read at all
+    pub corrupted: Vec<Hash>,
+    /// Blobs that backups refer to, but that aren't in storage
+    pub missing: Vec<Hash>,
+    /// Copies of blobs in a mirrored vault that are damaged, while another copy is intact
+    pub damaged_copies: Vec<DamagedCopy>,
+    /// Pack indexes that can't be read. The blobs in their packs are missing.
+    pub unreadable_indexes: Vec<String>,
+}
+
+impl Damage {
+    pub fn is_empty(&self) -> bool {
+        self.corrupted.is_empty()
+            && self.missing.is_empty()
+            && self.damaged_copies.is_empty()
+            && self.unreadable_indexes.is_empty()
+    }
+}
+
+/// A blob that is damaged or missing in one copy of a mirrored vault.
+#[derive(Debug)]
+pub(super) struct DamagedCopy {
+    pub hash: Hash,
+    /// Which copy is damaged, and which one the blob is intact in, starting from 0 for
+    /// the vault itself
+    pub copy: usize,
+    pub intact: usize,
+    pub copies: usize,
+}
+
+pub fn run(gargs: GlobalArgs, args: CliArgs) -> Result<()> {
+    let vault = Vault::open(gargs.vault_dir, gargs.key_file.as_deref())?;
+    let mut progress = ProgressReporter::new("verify");
+    let damage = find_damage(&vault, args.sample, &mut progress)?;
+    println!(
+        "Rehashed {} of {} stored blobs",
+        damage.checked, damage.stored
+    );
+    if damage.is_empty() {
+        println!("No damaged blobs found");
+        return Ok(());
+    }
+    print_damage(&vault, &damage);
+    let rebuildable = damage
+        .corrupted
+        .iter()
+        .chain(&damage.missing)
+        .filter(
+            |&&hash| match vault.storage.repair_from_parity(hash, true) {
+                Ok(rebuildable) => rebuildable,
+                Err(e) => {
+                    eprintln!("[warning] {e:#}");
+                    false
+                }
+            },
+        )
+        .count();
+    if rebuildable > 0 {
+        println!("{rebuildable} damaged blobs can be rebuilt from parity with the repair command");
+    }
+    bail!(
+        "found {} corrupted and {} missing blobs, {} damaged copies of blobs and {} unreadable \
+         pack indexes",
+        damage.corrupted.len(),
+        damage.missing.len(),
+        damage.damaged_copies.len(),
+        damage.unreadable_indexes.len()
+    )
+}
+
+/// Rehashes the stored blobs, or a random sample of them, and looks for blobs that backups
+/// refer to but that aren't stored. In a mirrored vault every copy is rehashed, and a blob
+/// only counts as corrupted if no copy of it is intact.
+pub(super) fn find_damage(
+    vault: &Vault,
+    sample: Option<f64>,
+    progress: &mut dyn Progress,
+) -> Result<Damage> {
+    let mut stored = vault.storage.list_blobs()?;
+    stored.sort_unstable_by_key(|hash| *hash.inner().as_bytes());
+    stored.dedup();
+
+    let mut to_check = stored.clone();
+    if let Some(percent) = sample {
+        let n = (to_check.len() as f64 * percent / 100.0).ceil() as usize;
+        to_check.shuffle(&mut rand::thread_rng());
+        to_check.truncate(n);
+    }
+
+    let mut damage = Damage {
+        checked: to_check.len(),
+        stored: stored.len(),
+        unreadable_indexes: vault.storage.unreadable_indexes(),
+        ..Default::default()
+    };
+    progress.expect(to_check.len() as u64, 0);
+    for hash in to_check {
+        let copies = vault.storage.rehash_copies(hash, progress);
+        let mut damaged = Vec::new();
+        for (i, copy) in copies.iter().enumerate() {
+            let which = match copies.len() {
+                1 => String::new(),
+                n => format!("copy {} of {n}: ", i + 1),
+            };
+            match copy {
+                Ok(actual) if *actual == hash => continue,
+                Ok(actual) => {
+                    eprintln!("[warning] {which}blob {hash} has the contents of {actual}")
+                }
+                Err(e) => eprintln!("[warning] {which}{e:#}"),
+            }
+            damaged.push(i);
+        }
+        if damaged.len() == copies.len() {
+            damage.corrupted.push(hash);
+            continue;
+        }
+        let intact = (0..copies.len()).find(|i| !damaged.contains(i)).unwrap();
+        for copy in damaged {
+            damage.damaged_copies.push(DamagedCopy {
+                hash,
+                copy,
+                intact,
+                copies: copies.len(),
+            });
+        }
+    }
+    progress.finish();
+
+    let stored: HashSet<Hash> = stored.into_iter().collect();
+    let mut missing = HashSet::new();
+    for (_, bkup) in vault.database.iter_backups() {
+        for hash in bkup.iter_blobs() {
+            if !stored.contains(&hash) && missing.insert(hash) {
+                damage.missing.push(hash);
+            }
+        }
+    }
+    Ok(damage)
+}
+
+/// Lists the damaged blobs, each with the backups and paths that refer to it.
+pub(super) fn print_damage(vault: &Vault, damage: &Damage) {
+    let damaged: HashSet<Hash> = damage
+        .corrupted
+        .iter()
+        .chain(&damage.missing)
+        .copied()
+        .chain(damage.damaged_copies.iter().map(|copy| copy.hash))
+        .collect();
+    let mut affected: HashMap<Hash, Vec<(&str, PathBuf)>> = HashMap::new();
+    for (name, bkup) in vault.database.iter_backups() {
+        for (path, hash) in bkup.iter_blob_refs() {
+            if damaged.contains(&hash) {
+                affected
+                    .entry(hash)
+                    .or_default()
+                    .push((name, path.to_owned()));
+            }
+        }
+    }
+
+    for (title, hashes) in [
+        ("Corrupted blobs:", &damage.corrupted),
+        ("Missing blobs:", &damage.missing),
+    ] {
+        if hashes.is_empty() {
+            continue;
+        }
+        println!("{title}");
+        for hash in hashes {
+            println!("- {hash}");
+            print_affected(affected.get(hash));
+        }
+    }
+
+    if !damage.unreadable_indexes.is_empty() {
+        println!("Unreadable pack indexes, whose blobs are missing:");
+        for key in &damage.unreadable_indexes {
+            println!("- {key}");
+        }
+    }
+
+    if damage.damaged_copies.is_empty() {
+        return;
+    }
+    println!("Damaged copies, intact in another copy:");
+    for damaged in &damage.damaged_copies {
+        let DamagedCopy {
+            hash, copy, copies, ..
+        } = *damaged;
+        print!("- {hash} in copy {} of {copies}", copy + 1);
+        match vault.storage.path_in_copy(hash, copy) {
+            Some(path) => println!(", {}", path.display()),
+            None => println!(),
+        }
+        print_affected(affected.get(&hash));
+    }
+}
+
+fn print_affected(affected: Option<&Vec<(&str, PathBuf)>>) {
+    for (name, path) in affected.into_iter().flatten() {
+        println!("    {name}: {}", path.display());
+    }
+}
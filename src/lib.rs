@@ -1,8 +1,15 @@
 #![allow(dead_code)]
 
 mod cmd;
+pub mod progress;
 mod util;
-mod vault;
+pub mod vault;
+
+pub use cmd::{
+    backup::{backup_into, ScanOptions, Summary},
+    restore::restore_from,
+};
+pub use util::{Hash, MTime};
 
 pub fn main() -> ! {
     cmd::cli_main()
@@ -0,0 +1,296 @@
+use std::{
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How often the progress line is redrawn on a terminal.
+const TTY_INTERVAL: Duration = Duration::from_millis(100);
+/// How often a progress line is logged when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+const COPY_BUF_SIZE: usize = 1 << 16;
+
+/// Receives updates from long running operations, such as backing up and restoring.
+/// All methods do nothing by default.
+pub trait Progress {
+    /// Sets how many files and bytes the operation is expected to go through in total,
+    /// eg. taken from the previous snapshot. Used to estimate the time remaining.
+    fn expect(&mut self, _files: u64, _bytes: u64) {}
+
+    /// A file was found while scanning. If it is unchanged, its contents won't be read.
+    fn scanned(&mut self, _path: &Path, _bytes: u64, _unchanged: bool) {}
+
+    /// The contents of path are about to be read.
+    fn reading(&mut self, _path: &Path) {}
+
+    /// Some bytes were read and hashed.
+    fn hashed(&mut self, _bytes: u64) {}
+
+    /// Some bytes were written to their destination.
+    fn copied(&mut self, _bytes: u64) {}
+
+    /// The operation is over.
+    fn finish(&mut self) {}
+}
+
+/// Discards all progress updates.
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Reports progress on stderr. When stderr is a terminal, a single status line is kept up to
+/// date. Otherwise, a plain line is logged every few seconds.
+pub struct ProgressReporter {
+    task: &'static str,
+    tty: bool,
+    start: Instant,
+    last_report: Instant,
+    expected_files: u64,
+    expected_bytes: u64,
+    files_scanned: u64,
+    files_read: u64,
+    bytes_scanned: u64,
+    bytes_unchanged: u64,
+    bytes_hashed: u64,
+    bytes_copied: u64,
+    current_path: PathBuf,
+}
+
+impl ProgressReporter {
+    pub fn new(task: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            task,
+            tty: io::stderr().is_terminal(),
+            start: now,
+            last_report: now,
+            expected_files: 0,
+            expected_bytes: 0,
+            files_scanned: 0,
+            files_read: 0,
+            bytes_scanned: 0,
+            bytes_unchanged: 0,
+            bytes_hashed: 0,
+            bytes_copied: 0,
+            current_path: PathBuf::new(),
+        }
+    }
+
+    fn maybe_report(&mut self) {
+        let interval = if self.tty { TTY_INTERVAL } else { LOG_INTERVAL };
+        if self.last_report.elapsed() >= interval {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let line = self.status_line();
+        let mut stderr = io::stderr().lock();
+        if self.tty {
+            let _ = write!(stderr, "\r\x1b[K{line}");
+        } else {
+            let _ = writeln!(stderr, "[{}] {line}", self.task);
+        }
+    }
+
+    fn status_line(&self) -> String {
+        let elapsed = self.start.elapsed();
+        let throughput = self.bytes_done() as f64 / elapsed.as_secs_f64().max(0.001);
+        let mut parts = Vec::new();
+        if self.files_scanned > 0 {
+            parts.push(format!(
+                "{} files ({}) scanned",
+                self.files_scanned,
+                format_bytes(self.bytes_scanned)
+            ));
+        }
+        if self.files_read > 0 {
+            parts.push(format!("{} files read", self.files_read));
+        }
+        if self.bytes_hashed > 0 {
+            parts.push(format!("{} hashed", format_bytes(self.bytes_hashed)));
+        }
+        if self.bytes_copied > 0 {
+            parts.push(format!("{} copied", format_bytes(self.bytes_copied)));
+        }
+        parts.push(format!("{}/s", format_bytes(throughput as u64)));
+        if let Some(eta) = self.eta(elapsed) {
+            parts.push(format!("ETA {}", format_duration(eta)));
+        }
+        if !self.current_path.as_os_str().is_empty() {
+            parts.push(self.current_path.display().to_string());
+        }
+        parts.join(", ")
+    }
+
+    /// The bytes that don't need any more work. Unchanged files are done once they are scanned,
+    /// and everything else once it has been hashed or copied, whichever is further along.
+    fn bytes_done(&self) -> u64 {
+        self.bytes_unchanged + self.bytes_hashed.max(self.bytes_copied)
+    }
+
+    /// Whether nothing happened at all, like when there was nothing to do.
+    fn is_idle(&self) -> bool {
+        self.files_scanned == 0 && self.files_read == 0 && self.bytes_done() == 0
+    }
+
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let done = self.bytes_done();
+        if self.expected_bytes > 0 && done > 0 && done < self.expected_bytes {
+            let left = (self.expected_bytes - done) as f64 / done as f64;
+            return Some(elapsed.mul_f64(left));
+        }
+        // Without any bytes to go by, fall back to counting files.
+        let files = self.files_scanned;
+        if done == 0 && files > 0 && files < self.expected_files {
+            let left = (self.expected_files - files) as f64 / files as f64;
+            return Some(elapsed.mul_f64(left));
+        }
+        None
+    }
+}
+
+impl Progress for ProgressReporter {
+    fn expect(&mut self, files: u64, bytes: u64) {
+        self.expected_files = files;
+        self.expected_bytes = bytes;
+    }
+
+    fn scanned(&mut self, path: &Path, bytes: u64, unchanged: bool) {
+        self.files_scanned += 1;
+        self.bytes_scanned += bytes;
+        if unchanged {
+            self.bytes_unchanged += bytes;
+        }
+        path.clone_into(&mut self.current_path);
+        self.maybe_report();
+    }
+
+    fn reading(&mut self, path: &Path) {
+        self.files_read += 1;
+        path.clone_into(&mut self.current_path);
+        self.maybe_report();
+    }
+
+    fn hashed(&mut self, bytes: u64) {
+        self.bytes_hashed += bytes;
+        self.maybe_report();
+    }
+
+    fn copied(&mut self, bytes: u64) {
+        self.bytes_copied += bytes;
+        self.maybe_report();
+    }
+
+    fn finish(&mut self) {
+        // A line of zeros would only be noise
+        if self.is_idle() {
+            return;
+        }
+        self.current_path = PathBuf::new();
+        self.report();
+        if self.tty {
+            eprintln!();
+        }
+    }
+}
+
+/// Like io::copy, but reports the bytes written as copied.
+pub fn copy(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    progress: &mut dyn Progress,
+) -> io::Result<u64> {
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+        progress.copied(n as u64);
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(dur: Duration) -> String {
+    let secs = dur.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h{m:02}m")
+    } else if m > 0 {
+        format!("{m}m{s:02}s")
+    } else {
+        format!("{s}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_from_bytes_or_files() {
+        let mut progress = ProgressReporter::new("test");
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(progress.eta(elapsed), None);
+
+        progress.expect(10, 1000);
+        progress.scanned(Path::new("a"), 100, false);
+        assert_eq!(progress.eta(elapsed), Some(Duration::from_secs(90)));
+
+        // Unchanged files count as done once scanned
+        progress.scanned(Path::new("b"), 150, true);
+        progress.hashed(50);
+        assert_eq!(progress.eta(elapsed), Some(Duration::from_secs(40)));
+
+        // Past the expected totals, there is no estimate
+        progress.copied(900);
+        assert_eq!(progress.eta(elapsed), None);
+
+        let mut progress = ProgressReporter::new("test");
+        progress.expect(4, 0);
+        progress.scanned(Path::new("a"), 0, false);
+        assert_eq!(progress.eta(elapsed), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn idle() {
+        let mut progress = ProgressReporter::new("test");
+        assert!(progress.is_idle());
+        progress.expect(10, 1000);
+        assert!(progress.is_idle());
+        progress.copied(100);
+        assert!(!progress.is_idle());
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 << 30), "5.0 GiB");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(61)), "1m01s");
+        assert_eq!(format_duration(Duration::from_secs(7260)), "2h01m");
+    }
+}
@@ -3,8 +3,7 @@ use serde::{de::Visitor, Deserialize, Serialize};
 use std::{
     env::current_dir,
     fmt::{Debug, Display},
-    fs::read_dir,
-    io,
+    fs::{read_dir, File},
     path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
@@ -19,6 +18,13 @@ pub fn ensure_dir_exists_and_is_empty(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Makes changes to the entries of dir durable.
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context_2("fsync", dir)
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
 pub struct MTime {
     sec: u64,
@@ -39,6 +45,12 @@ impl Ord for MTime {
     }
 }
 
+impl From<MTime> for SystemTime {
+    fn from(mtime: MTime) -> Self {
+        SystemTime::UNIX_EPOCH + Duration::new(mtime.sec, mtime.nano)
+    }
+}
+
 impl From<SystemTime> for MTime {
     fn from(st: SystemTime) -> Self {
         let dur = st.duration_since(SystemTime::UNIX_EPOCH).unwrap();
@@ -65,16 +77,17 @@ impl<C: Context<T, E>, T, E> ContextExt<T, E> for C {}
 pub struct Hash(blake3::Hash);
 
 impl Hash {
-    pub fn of_file<P: AsRef<Path>>(path: P) -> io::Result<Hash> {
-        let hash = blake3::Hasher::new().update_mmap(path)?.finalize();
-        Ok(Hash(hash))
-    }
-
     pub fn inner(&self) -> blake3::Hash {
         self.0
     }
 }
 
+impl From<blake3::Hash> for Hash {
+    fn from(hash: blake3::Hash) -> Self {
+        Hash(hash)
+    }
+}
+
 impl Serialize for Hash {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
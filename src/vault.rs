@@ -1,11 +1,23 @@
+pub mod backend;
 pub mod backup;
+pub mod config;
+pub mod crypto;
 pub mod database;
 pub mod lock;
+pub mod pack;
+pub mod parity;
 pub mod storage;
 
-use eyre::{Context, Result};
-use std::path::{Path, PathBuf};
+use eyre::{ensure, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
+use crate::util::ContextExt;
+use backend::{Backend, LocalBackend, MemoryBackend, MirrorBackend, S3Backend, S3Config};
+use config::{Config, CONFIG_KEY};
+use crypto::Cipher;
 use database::Database;
 use storage::Storage;
 
@@ -15,38 +27,204 @@ use lock::DirectoryLock;
 pub struct Vault {
     pub database: Database,
     pub storage: Storage,
-    lock: DirectoryLock,
+    /// None for vaults that aren't kept in a local directory
+    lock: Option<DirectoryLock>,
 }
 
 impl Vault {
-    pub fn open(vault_dir: Option<PathBuf>) -> Result<Self> {
-        match vault_dir {
-            Some(provided) => Self::open_dir(provided),
-            None => match std::env::var_os("VAULT_DIR") {
-                Some(var) => Self::open_dir(var),
-                None => Self::open_dir(std::env::current_dir().unwrap()),
-            },
-        }
+    /// Opens and locks the vault, which is either a directory or an `s3://BUCKET/PREFIX` URL.
+    /// Encrypted vaults are unlocked with key_file, or a passphrase.
+    /// Mirrors that haven't been synced since they were added or replaced are left out.
+    pub fn open(vault_dir: Option<PathBuf>, key_file: Option<&Path>) -> Result<Self> {
+        Self::open_dir(resolve_dir(vault_dir), key_file, false, &[])
+    }
+
+    /// Like `open`, but uses all of the vault's mirrors, and fails if one of them holds no
+    /// copy of the vault. Only `new_mirrors`, which were just checked to be empty, may be
+    /// incomplete.
+    pub fn open_all_mirrors(
+        vault_dir: Option<PathBuf>,
+        key_file: Option<&Path>,
+        new_mirrors: &[PathBuf],
+    ) -> Result<Self> {
+        Self::open_dir(resolve_dir(vault_dir), key_file, true, new_mirrors)
     }
 
-    fn open_dir(vault_dir: impl AsRef<Path>) -> Result<Self> {
+    fn open_dir(
+        vault_dir: impl AsRef<Path>,
+        key_file: Option<&Path>,
+        all_mirrors: bool,
+        new_mirrors: &[PathBuf],
+    ) -> Result<Self> {
         let vault_dir = vault_dir.as_ref();
+        if let Some(url) = s3_url(vault_dir) {
+            let backend = S3Backend::new(S3Config::from_url(url)?)?;
+            return Self::open_backend(Box::new(backend), key_file);
+        }
         let lock = DirectoryLock::new(vault_dir);
         lock.blocking_lock()?;
 
-        let database = Database::load(vault_dir).context("Loading database")?;
-        let storage = Storage::new(vault_dir);
+        match Self::load(vault_dir, key_file, all_mirrors, new_mirrors) {
+            Ok((database, storage)) => Ok(Vault {
+                database,
+                storage,
+                lock: Some(lock),
+            }),
+            Err(e) => {
+                // There is no Vault to unlock it on drop yet
+                let _ = lock.unlock();
+                Err(e)
+            }
+        }
+    }
+
+    fn load(
+        vault_dir: &Path,
+        key_file: Option<&Path>,
+        all_mirrors: bool,
+        new_mirrors: &[PathBuf],
+    ) -> Result<(Database, Storage)> {
+        let backend = LocalBackend::new(vault_dir);
+        let config = Config::load(&backend)?;
+        if !config.mirrors.is_empty() {
+            // Writes would only reach this copy, without the vault's lock
+            let canonical = fs::canonicalize(vault_dir).context_2("canonicalize", vault_dir)?;
+            ensure!(
+                !config
+                    .mirrors
+                    .iter()
+                    .any(|dir| fs::canonicalize(dir).is_ok_and(|dir| dir == canonical)),
+                "{} is a mirror, open the vault it mirrors instead. If that is lost, copy the \
+                 mirror to a new directory and open the copy",
+                vault_dir.display()
+            );
+        }
+        Self::remove_stale_tmp_files(&backend)?;
+        let backend = backend.with_immutable(config.immutable);
+        if config.mirrors.is_empty() {
+            return Self::load_from(Box::new(backend), key_file);
+        }
+
+        let mut replicas: Vec<Box<dyn Backend>> = vec![Box::new(backend)];
+        for dir in &config.mirrors {
+            if !is_mirror_of(&config, dir)? && !new_mirrors.contains(dir) {
+                let dir = dir.display();
+                // Writing to an unmounted disk's mount point would fill the wrong disk
+                ensure!(
+                    !all_mirrors,
+                    "mirror {dir} is missing or incomplete. If its disk was replaced or its \
+                     first resync was interrupted, empty it and run resync --add {dir}"
+                );
+                eprintln!(
+                    "[warning] mirror {dir} is missing or incomplete, run resync. \
+                     Continuing without it."
+                );
+                continue;
+            }
+            let mirror = LocalBackend::new(dir);
+            Self::remove_stale_tmp_files(&mirror)?;
+            replicas.push(Box::new(mirror.with_immutable(config.immutable)));
+        }
+        Self::load_from(Box::new(MirrorBackend::new(replicas)), key_file)
+    }
+
+    fn remove_stale_tmp_files(backend: &LocalBackend) -> Result<()> {
+        let removed = backend.remove_stale_tmp_files()?;
+        if removed > 0 {
+            eprintln!("Removed {removed} temporary files left behind by an interrupted backup");
+        }
+        Ok(())
+    }
+
+    fn load_from(
+        backend: Box<dyn Backend>,
+        key_file: Option<&Path>,
+    ) -> Result<(Database, Storage)> {
+        let config = Config::load(&*backend)?;
+        let cipher = match &config.encryption {
+            Some(wrapped) => {
+                let secret = crypto::read_secret(wrapped.source, key_file, false)?;
+                Some(Cipher::unwrap(wrapped, &secret)?)
+            }
+            None => None,
+        };
+        let database = Database::load(&*backend, cipher.clone()).context("Loading database")?;
+        let storage = Storage::new(backend, &config, cipher)?;
+        Ok((database, storage))
+    }
+
+    /// Opens a vault kept in backend. Unlike `open`, the vault is not locked, so the caller
+    /// must make sure nothing else uses the backend at the same time.
+    pub fn open_backend(backend: Box<dyn Backend>, key_file: Option<&Path>) -> Result<Self> {
+        let (database, storage) = Self::load_from(backend, key_file)?;
         Ok(Vault {
             database,
             storage,
-            lock,
+            lock: None,
         })
     }
+
+    /// Creates a new, empty vault in backend. `cipher` must be the one whose keys are
+    /// wrapped in `config.encryption`, if the vault is encrypted.
+    pub fn create(
+        backend: Box<dyn Backend>,
+        config: Config,
+        cipher: Option<Cipher>,
+    ) -> Result<Self> {
+        config.write(&*backend)?;
+        let vault = Vault {
+            database: Database::new(cipher.clone()),
+            storage: Storage::new(backend, &config, cipher)?,
+            lock: None,
+        };
+        vault.write_database()?;
+        Ok(vault)
+    }
+
+    /// Creates a vault with the default config that is kept in memory, for tests and
+    /// embedding. Keep a clone of backend to inspect what is stored.
+    pub fn in_memory(backend: MemoryBackend) -> Result<Self> {
+        Self::create(Box::new(backend), Config::default(), None)
+    }
+
+    /// Stores pending blobs, then the database that refers to them.
+    pub fn write_database(&self) -> Result<()> {
+        self.storage.flush()?;
+        self.database.write(self.storage.backend())
+    }
+}
+
+/// The vault location given on the command line, or else in $VAULT_DIR, or else the current
+/// directory.
+pub fn resolve_dir(vault_dir: Option<PathBuf>) -> PathBuf {
+    vault_dir
+        .or_else(|| std::env::var_os("VAULT_DIR").map(PathBuf::from))
+        .unwrap_or_else(|| std::env::current_dir().unwrap())
+}
+
+/// Checks whether dir holds a copy of the vault with this config, as a mirror that was synced
+/// at least once. Fails if it holds another vault.
+pub fn is_mirror_of(config: &Config, dir: &Path) -> Result<bool> {
+    if !dir.join(CONFIG_KEY).is_file() {
+        return Ok(false);
+    }
+    let mirror_config = Config::load(&LocalBackend::new(dir))?;
+    ensure!(
+        config.is_same_vault(&mirror_config),
+        "{} holds another vault, not a copy of this one",
+        dir.display()
+    );
+    Ok(true)
+}
+
+/// Returns the vault location as a URL if it names an S3 bucket rather than a directory.
+pub fn s3_url(location: &Path) -> Option<&str> {
+    location.to_str().filter(|url| url.starts_with("s3://"))
 }
 
 impl Drop for Vault {
     fn drop(&mut self) {
-        if let Err(e) = self.lock.unlock() {
+        if let Some(Err(e)) = self.lock.as_ref().map(|lock| lock.unlock()) {
             eprintln!("Unlocking vault failed:");
             eprintln!("{e}");
         }
@@ -0,0 +1,57 @@
+mod local;
+mod memory;
+mod mirror;
+mod s3;
+
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use mirror::{resync, MirrorBackend};
+pub use s3::{S3Backend, S3Config};
+
+use eyre::Result;
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+/// Where the objects of a vault are kept: its config, database, blobs and packs.
+///
+/// Keys are relative, `/`-separated paths like `data/ab/<hash>`. Objects are written whole and
+/// never modified, so a backend only has to make each `put` atomic: a reader either sees the
+/// whole object or none of it, even after a crash.
+pub trait Backend: Debug + Send + Sync {
+    /// Stores an object, replacing any object with the same key.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Opens an object for reading, or returns None if it doesn't exist.
+    fn get(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>>;
+
+    /// Reads `len` bytes of an object, starting at `offset`.
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        let Some(mut object) = self.get(key)? else {
+            eyre::bail!("{key} does not exist");
+        };
+        io::copy(&mut (&mut object).take(offset), &mut io::sink())?;
+        Ok(Box::new(object.take(len)))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Deletes an object. Deleting an object that doesn't exist is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Returns the keys of all objects whose key starts with `prefix`.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Where an object is on the local filesystem, if the backend keeps objects as plain files.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// The backends that each hold a copy of every object, if this one mirrors them.
+    /// Empty otherwise.
+    fn replicas(&self) -> Vec<&dyn Backend> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,229 @@
+use eyre::{Context, Result};
+use std::{
+    fs::{self, File, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{fd::AsRawFd, unix::fs::PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use walkdir::WalkDir;
+
+use super::Backend;
+use crate::util::{sync_dir, ContextExt};
+
+/// Temporary files are written here, on the same filesystem as the objects they become.
+const TMP_DIR_NAME: &str = "data";
+const TMP_FILE_PREFIX: &str = "tmp-";
+const OBJECT_MODE: u32 = 0o444;
+/// From linux/fs.h, libc doesn't have it
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Keeps every object as a file under the vault directory, at the path given by its key.
+///
+/// Objects are read-only, so that one reached through a symlink into the vault, like the
+/// ones `mount` creates, can't be edited in place. Root ignores permissions, so objects can
+/// be made immutable too. `put` and `delete` lift that before they replace or remove one.
+#[derive(Debug)]
+pub struct LocalBackend {
+    root: PathBuf,
+    tmp_counter: AtomicU64,
+    /// Only root can set or clear the immutable flag
+    is_root: bool,
+    immutable: bool,
+}
+
+impl LocalBackend {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            tmp_counter: AtomicU64::new(0),
+            // SAFETY: geteuid can't fail
+            is_root: unsafe { libc::geteuid() } == 0,
+            immutable: false,
+        }
+    }
+
+    /// Makes the objects that are put immutable, like `chattr +i`, when running as root and
+    /// the filesystem supports it.
+    pub fn with_immutable(mut self, immutable: bool) -> Self {
+        self.immutable = immutable && self.is_root;
+        self
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.root.join(TMP_DIR_NAME)
+    }
+
+    fn new_tmp_path(&self) -> PathBuf {
+        let n = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        self.tmp_dir().join(format!("{TMP_FILE_PREFIX}{n}"))
+    }
+
+    /// Removes temporary files left behind by an interrupted `put`.
+    /// Must only be called while holding the vault lock. Returns how many were removed.
+    pub fn remove_stale_tmp_files(&self) -> Result<usize> {
+        let tmp_dir = self.tmp_dir();
+        let entries = match fs::read_dir(&tmp_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).context_2("read_dir", &tmp_dir),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry.context_2("read_dir", &tmp_dir)?.path();
+            if is_tmp_file(&path) {
+                fs::remove_file(&path).context_2("remove_file", &path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl Backend for LocalBackend {
+    /// Writes to a temporary file, syncs it and renames it into place, then syncs the
+    /// directories involved, so that an object at its final path is always complete.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let dest = self.path(key);
+        let dir = dest.parent().unwrap();
+        let tmp_dir = self.tmp_dir();
+        for dir in [dir, &tmp_dir] {
+            if !dir.exists() {
+                fs::create_dir_all(dir).context_2("mkdir", dir)?;
+                sync_dir(dir.parent().unwrap())?;
+            }
+        }
+
+        let tmp_path = self.new_tmp_path();
+        let res = File::create(&tmp_path).and_then(|mut tmp| {
+            tmp.write_all(data)?;
+            tmp.set_permissions(Permissions::from_mode(OBJECT_MODE))?;
+            tmp.sync_all()
+        });
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e).context_2("writing", &tmp_path);
+        }
+
+        let tmp_disp = tmp_path.display();
+        let dest_disp = dest.display();
+        if self.is_root {
+            set_immutable(&dest, false)?;
+        }
+        fs::rename(&tmp_path, &dest)
+            .with_context(|| format!("renaming {tmp_disp} to {dest_disp}"))?;
+        if self.immutable {
+            // Best effort, it may not be supported or allowed
+            let _ = set_immutable(&dest, true);
+        }
+        // Both directories changed, the temporary file's and the object's.
+        sync_dir(&tmp_dir)?;
+        sync_dir(dir)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let path = self.path(key);
+        match File::open(&path) {
+            Ok(f) => Ok(Some(Box::new(f))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context_2("open", &path),
+        }
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        let path = self.path(key);
+        let mut f = File::open(&path).context_2("open", &path)?;
+        f.seek(SeekFrom::Start(offset)).context_2("seek", &path)?;
+        Ok(Box::new(f.take(len)))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        let path = self.path(key);
+        path.try_exists().context_2("stat", &path)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        if self.is_root {
+            set_immutable(&path, false)?;
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res.context_2("remove_file", &path),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Walk from the deepest directory the prefix names, and filter on the rest
+        let dir = match prefix.rfind('/') {
+            Some(i) => self.root.join(&prefix[..i]),
+            None => self.root.clone(),
+        };
+        let mut keys = Vec::new();
+        for entry in WalkDir::new(&dir).min_depth(1) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.io_error().map(|e| e.kind()) == Some(io::ErrorKind::NotFound) => break,
+                Err(e) => return Err(e).context_2("listing", &dir),
+            };
+            if !entry.file_type().is_file() || is_tmp_file(entry.path()) {
+                continue;
+            }
+            let Ok(rel) = entry.path().strip_prefix(&self.root) else {
+                continue;
+            };
+            match rel.to_str() {
+                Some(key) if key.starts_with(prefix) => keys.push(key.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(keys)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        let path = self.path(key);
+        path.is_file().then_some(path)
+    }
+}
+
+fn is_tmp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(TMP_FILE_PREFIX))
+}
+
+/// Sets or clears the immutable flag of a file, like `chattr +i`. Missing files, and
+/// filesystems without the flag, are ignored.
+fn set_immutable(path: &Path, immutable: bool) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context_2("open", path),
+    };
+    let fd = file.as_raw_fd();
+    let mut flags: libc::c_int = 0;
+    // SAFETY: fd is open, and both ioctls take a pointer to an int
+    let mut ret = unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut flags) };
+    if ret == 0 {
+        let new_flags = match immutable {
+            true => flags | FS_IMMUTABLE_FL,
+            false => flags & !FS_IMMUTABLE_FL,
+        };
+        if new_flags == flags {
+            return Ok(());
+        }
+        // SAFETY: as above
+        ret = unsafe { libc::ioctl(fd, libc::FS_IOC_SETFLAGS, &new_flags) };
+    }
+    if ret == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP)) => Ok(()),
+        e => Err(e).context_2("chattr", path),
+    }
+}
@@ -0,0 +1,76 @@
+use eyre::Result;
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+    sync::{Arc, Mutex},
+};
+
+use super::Backend;
+
+/// Keeps every object in memory, for tests and for embedding a vault that doesn't need to
+/// outlive the process. Clones share the same objects, so a clone kept aside can be used to
+/// inspect what a vault stored.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    objects: Arc<Mutex<BTreeMap<String, Arc<[u8]>>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The keys of all objects, in order.
+    pub fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The stored bytes of an object.
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|data| data.to_vec())
+    }
+
+    /// Replaces the stored bytes of an object, eg. to simulate corruption.
+    pub fn set_object(&self, key: &str, data: &[u8]) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), data.into());
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.set_object(key, data);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let data = self.objects.lock().unwrap().get(key).cloned();
+        Ok(data.map(|data| Box::new(Cursor::new(data)) as _))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(key))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let objects = self.objects.lock().unwrap();
+        let keys = objects
+            .range(prefix.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok(keys)
+    }
+}
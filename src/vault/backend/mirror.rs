@@ -0,0 +1,174 @@
+use eyre::{Context, Result};
+use std::{
+    collections::{BTreeSet, HashSet},
+    io::Read,
+    path::PathBuf,
+};
+
+use super::Backend;
+use crate::{
+    progress::Progress,
+    vault::{
+        config::CONFIG_KEY,
+        database::DATABASE_KEY,
+        pack::PACKS_PREFIX,
+        parity::PARITY_PREFIX,
+        storage::{DATA_PREFIX, QUARANTINE_PREFIX},
+    },
+};
+
+/// The keys of the objects that make up a vault. A mirror can be in a directory that holds
+/// other files too, which resync must leave alone.
+fn is_vault_key(key: &str) -> bool {
+    key == CONFIG_KEY
+        || key == DATABASE_KEY
+        || [DATA_PREFIX, PACKS_PREFIX, PARITY_PREFIX, QUARANTINE_PREFIX]
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+}
+
+/// Keeps a copy of every object in each of several backends, like RAID1. Writes go to all of
+/// them, and reads use the first copy that exists.
+#[derive(Debug)]
+pub struct MirrorBackend {
+    replicas: Vec<Box<dyn Backend>>,
+}
+
+impl MirrorBackend {
+    pub fn new(replicas: Vec<Box<dyn Backend>>) -> Self {
+        Self { replicas }
+    }
+}
+
+impl Backend for MirrorBackend {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        for replica in &self.replicas {
+            replica.put(key, data)?;
+        }
+        Ok(())
+    }
+
+    /// A copy that can't be opened is skipped if another one can be.
+    fn get(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let mut error = None;
+        for replica in &self.replicas {
+            match replica.get(key) {
+                Ok(Some(object)) => return Ok(Some(object)),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("[warning] {e:#}");
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        for replica in &self.replicas {
+            if replica.exists(key)? {
+                return replica.get_range(key, offset, len);
+            }
+        }
+        eyre::bail!("{key} does not exist")
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        for replica in &self.replicas {
+            if replica.exists(key)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        for replica in &self.replicas {
+            replica.delete(key)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = BTreeSet::new();
+        for replica in &self.replicas {
+            keys.extend(replica.list(prefix)?);
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        self.replicas
+            .iter()
+            .find_map(|replica| replica.local_path(key))
+    }
+
+    fn replicas(&self) -> Vec<&dyn Backend> {
+        self.replicas.iter().map(|replica| &**replica).collect()
+    }
+}
+
+/// Makes every mirror a copy of the first replica, which holds the vault. Objects a mirror is
+/// missing are copied to it, as are the database and config where they differ, and objects
+/// the vault no longer has, like repacked packs, are deleted from it. Anything else in the
+/// mirror's directory is left alone. Returns how many objects were copied and deleted.
+pub fn resync(replicas: &[&dyn Backend], progress: &mut dyn Progress) -> Result<(usize, usize)> {
+    let Some((primary, mirrors)) = replicas.split_first() else {
+        return Ok((0, 0));
+    };
+    let in_primary: HashSet<String> = primary
+        .list("")?
+        .into_iter()
+        .filter(|key| is_vault_key(key))
+        .collect();
+    let mut keys: Vec<&String> = in_primary.iter().collect();
+    // A copy without the config is treated as incomplete, so the config goes last
+    keys.sort_by_key(|key| (*key == CONFIG_KEY, *key == DATABASE_KEY));
+
+    let (mut copied, mut deleted) = (0, 0);
+    for mirror in mirrors {
+        let listed: HashSet<String> = mirror
+            .list("")?
+            .into_iter()
+            .filter(|key| is_vault_key(key))
+            .collect();
+        // Deleting first keeps the mirror consistent with the database it still has
+        for key in &listed {
+            if !in_primary.contains(key) {
+                mirror.delete(key)?;
+                deleted += 1;
+            }
+        }
+        for &key in &keys {
+            let mutable = key == CONFIG_KEY || key == DATABASE_KEY;
+            if listed.contains(key) && !mutable {
+                continue;
+            }
+            let Some(data) = read(*primary, key)? else {
+                continue;
+            };
+            if mutable && read(*mirror, key)?.as_ref() == Some(&data) {
+                continue;
+            }
+            mirror.put(key, &data)?;
+            progress.copied(data.len() as u64);
+            copied += 1;
+        }
+    }
+    progress.finish();
+    Ok((copied, deleted))
+}
+
+fn read(backend: &dyn Backend, key: &str) -> Result<Option<Vec<u8>>> {
+    let Some(mut object) = backend.get(key)? else {
+        return Ok(None);
+    };
+    let mut data = Vec::new();
+    object
+        .read_to_end(&mut data)
+        .with_context(|| format!("reading {key}"))?;
+    Ok(Some(data))
+}
@@ -2,10 +2,11 @@ use derive_more::{Deref, DerefMut};
 use fieldmap::ClonedFieldMap;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     path::{Path, PathBuf},
 };
 
+use super::storage::{self, StoredFile};
 use crate::util::{Hash, MTime};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,6 +14,22 @@ pub struct Backup {
     files: BackupFiles,
     directories: BTreeSet<PathBuf>,
     symlinks: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    specials: BTreeMap<PathBuf, SpecialFile>,
+    /// Maps each extra path of a hardlinked file to the path it is stored under in `files`.
+    #[serde(default)]
+    hardlinks: BTreeMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    xattrs: BTreeMap<PathBuf, Xattrs>,
+    /// Entries that were left out of the backup, and why.
+    #[serde(default)]
+    skipped: BTreeMap<PathBuf, SkipReason>,
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Backup {
@@ -21,6 +38,10 @@ impl Backup {
             files: BackupFiles::new(),
             directories: BTreeSet::new(),
             symlinks: BTreeMap::new(),
+            specials: BTreeMap::new(),
+            hardlinks: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+            skipped: BTreeMap::new(),
         }
     }
 
@@ -32,6 +53,42 @@ impl Backup {
         self.symlinks.insert(link_name, target);
     }
 
+    pub fn insert_special(&mut self, path: PathBuf, special: SpecialFile) {
+        self.specials.insert(path, special);
+    }
+
+    pub fn insert_hardlink(&mut self, path: PathBuf, original: PathBuf) {
+        self.hardlinks.insert(path, original);
+    }
+
+    /// Removes the hardlinks to original, returning their paths.
+    pub fn remove_hardlinks_to(&mut self, original: &Path) -> Vec<PathBuf> {
+        let mut links = Vec::new();
+        self.hardlinks.retain(|path, to| {
+            let linked = to == original;
+            if linked {
+                links.push(path.clone());
+            }
+            !linked
+        });
+        links
+    }
+
+    pub fn insert_xattrs(&mut self, path: PathBuf, xattrs: Xattrs) {
+        if !xattrs.is_empty() {
+            self.xattrs.insert(path, xattrs);
+        }
+    }
+
+    pub fn insert_skipped(&mut self, path: PathBuf, reason: SkipReason) {
+        // A directory whose contents can't be read was already added when it was found,
+        // but it must not be restored as if it were empty
+        self.directories.remove(&path);
+        // There is nothing to restore them on
+        self.xattrs.remove(&path);
+        self.skipped.insert(path, reason);
+    }
+
     pub fn insert_file(&mut self, backup_file: BackupFile) {
         self.files.insert(backup_file);
     }
@@ -48,39 +105,167 @@ impl Backup {
         self.symlinks.iter()
     }
 
-    pub fn get_file(&self, ino: u64) -> Option<&BackupFile> {
-        self.files.get(&ino)
+    pub fn iter_specials(&self) -> std::collections::btree_map::Iter<'_, PathBuf, SpecialFile> {
+        self.specials.iter()
+    }
+
+    /// Iterates over (path, original) pairs, where original is the path of the file in
+    /// `iter_files` that path is a hardlink to.
+    pub fn iter_hardlinks(&self) -> std::collections::btree_map::Iter<'_, PathBuf, PathBuf> {
+        self.hardlinks.iter()
+    }
+
+    pub fn iter_xattrs(&self) -> std::collections::btree_map::Iter<'_, PathBuf, Xattrs> {
+        self.xattrs.iter()
+    }
+
+    pub fn iter_skipped(&self) -> std::collections::btree_map::Iter<'_, PathBuf, SkipReason> {
+        self.skipped.iter()
+    }
+
+    /// Every blob in storage the backup refers to, including chunks and extended attribute
+    /// values. Blobs that are used more than once are repeated.
+    pub fn iter_blobs(&self) -> impl Iterator<Item = Hash> + '_ {
+        self.iter_blob_refs().map(|(_, hash)| hash)
+    }
+
+    /// Like `iter_blobs`, along with the path that refers to each blob.
+    pub fn iter_blob_refs(&self) -> impl Iterator<Item = (&Path, Hash)> + '_ {
+        let files = self
+            .iter_files()
+            .flat_map(|f| f.blobs().iter().map(|hash| (f.path.as_path(), *hash)));
+        let xattrs = self.xattrs.iter().flat_map(|(path, xattrs)| {
+            xattrs.values().filter_map(move |value| match value {
+                XattrValue::Stored(hash) => Some((path.as_path(), *hash)),
+                XattrValue::Inline(_) => None,
+            })
+        });
+        files.chain(xattrs)
+    }
+
+    /// The sum of the sizes of all files, not counting hardlinks twice.
+    pub fn total_file_size(&self) -> u64 {
+        self.iter_files().map(|f| f.size).sum()
+    }
+
+    /// Looks up a file by its path in the backup, following hardlinks to their original.
+    pub fn find_file(&self, path: &Path) -> Option<&BackupFile> {
+        let path = self.hardlinks.get(path).map_or(path, |original| original);
+        self.files.get(&path.to_path_buf())
+    }
+
+    /// The files of the backup by device and inode. Files reached through more than one
+    /// dereferenced symlink share them, and only one of those is kept.
+    pub fn files_by_id(&self) -> HashMap<FileId, &BackupFile> {
+        self.iter_files().map(|f| (f.id, f)).collect()
     }
 }
 
+/// The extended attributes of a path, including POSIX ACLs, by attribute name.
+pub type Xattrs = BTreeMap<String, XattrValue>;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
-pub struct BackupFile {
+pub enum XattrValue {
+    Inline(Vec<u8>),
+    /// Large values are kept in storage, so they are deduplicated like file contents.
+    Stored(Hash),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum SkipReason {
+    /// The entry could not be read. Holds the error message.
+    Error(String),
+    /// The directory is tagged as a cache with a CACHEDIR.TAG.
+    CacheDir,
+    /// The directory is on a pseudo-filesystem, like proc or sysfs.
+    PseudoFs,
+    /// The file is larger than the --max-file-size of the backup.
+    TooLarge { size: u64 },
+    /// The file is smaller than the --min-file-size of the backup.
+    TooSmall { size: u64 },
+    /// The file was last modified before the --newer-than time of the backup.
+    TooOld { mtime: MTime },
+    /// The symlink points to a directory it is in, so following it would never end.
+    /// `target` is that directory, in the backup.
+    SymlinkLoop { target: PathBuf },
+}
+
+/// A file that is neither a regular file, a directory, nor a symlink.
+/// Only its type and device number are kept, which is enough to recreate it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SpecialFile {
+    Fifo,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+/// The device and inode of a file on the machine it was backed up from.
+/// Backups made before the device was recorded have it as 0.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct FileId {
+    #[serde(default)]
+    pub dev: u64,
     pub ino: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BackupFile {
+    #[serde(flatten)]
+    pub id: FileId,
     pub path: PathBuf,
     pub hash: Hash,
     pub mtime: MTime,
+    #[serde(default)]
+    pub size: u64,
+    /// The blobs of a large file that was split into chunks. The file itself isn't stored
+    /// under its hash then.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Hash>,
 }
 
 impl BackupFile {
-    fn ino(&self) -> &u64 {
-        &self.ino
+    pub fn new(path: PathBuf, id: FileId, mtime: MTime, size: u64, stored: StoredFile) -> Self {
+        Self {
+            id,
+            path,
+            hash: stored.hash,
+            mtime,
+            size,
+            chunks: stored.chunks,
+        }
+    }
+
+    pub fn stored(&self) -> StoredFile {
+        StoredFile {
+            hash: self.hash,
+            chunks: self.chunks.clone(),
+        }
+    }
+
+    /// The blobs whose contents make up the file, in order.
+    pub fn blobs(&self) -> &[Hash] {
+        storage::blobs(&self.hash, &self.chunks)
+    }
+
+    fn path(&self) -> &PathBuf {
+        &self.path
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Deref, DerefMut)]
 pub struct BackupFiles(
-    #[serde(deserialize_with = "BackupFiles::deserialize")] ClonedFieldMap<BackupFile, u64>,
+    #[serde(deserialize_with = "BackupFiles::deserialize")] ClonedFieldMap<BackupFile, PathBuf>,
 );
 
 impl BackupFiles {
     fn new() -> Self {
-        Self(ClonedFieldMap::new(BackupFile::ino))
+        Self(ClonedFieldMap::new(BackupFile::path))
     }
 
-    fn deserialize<'de, D>(deserializer: D) -> Result<ClonedFieldMap<BackupFile, u64>, D::Error>
+    fn deserialize<'de, D>(deserializer: D) -> Result<ClonedFieldMap<BackupFile, PathBuf>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        ClonedFieldMap::deserialize(BackupFile::ino, deserializer)
+        ClonedFieldMap::deserialize(BackupFile::path, deserializer)
     }
 }
@@ -0,0 +1,65 @@
+use super::{backend::Backend, crypto::WrappedKeys};
+
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+pub(super) const CONFIG_KEY: &str = "config.json";
+
+/// Settings that are chosen once, when the vault is created.
+/// Vaults created before this file existed use the defaults.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Config {
+    /// zstd level new blobs are compressed with. None stores blobs uncompressed.
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+
+    /// The encrypted keys of an encrypted vault.
+    #[serde(default)]
+    pub encryption: Option<WrappedKeys>,
+
+    /// Reed–Solomon parity stored for each blob object and pack, as a percentage of its size,
+    /// so that bitrot can be repaired without another copy. None stores no parity.
+    #[serde(default)]
+    pub parity: Option<u8>,
+
+    /// Directories, usually on other disks, that each hold a copy of every object in the vault.
+    /// Writes go to all of them, and reads fall back to another copy when one is missing
+    /// or damaged.
+    #[serde(default)]
+    pub mirrors: Vec<PathBuf>,
+
+    /// Whether objects in a local directory are made immutable when running as root,
+    /// on top of being read-only.
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+impl Config {
+    pub fn load(backend: &dyn Backend) -> Result<Self> {
+        match backend.get(CONFIG_KEY)? {
+            Some(object) => serde_json::from_reader(object).context("parsing config file"),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Whether `other`, read from a mirror, is the config of this same vault. The mirrors are
+    /// left out, since a mirror only gets the current list at its next resync.
+    pub fn is_same_vault(&self, other: &Config) -> bool {
+        let settings = |config: &Config| {
+            let config = Config {
+                mirrors: Vec::new(),
+                ..config.clone()
+            };
+            serde_json::to_value(config).ok()
+        };
+        settings(self) == settings(other)
+    }
+
+    pub fn write(&self, backend: &dyn Backend) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        backend
+            .put(CONFIG_KEY, &data)
+            .context("writing config file")
+    }
+}
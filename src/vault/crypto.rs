@@ -0,0 +1,330 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, AeadCore, KeyInit, OsRng,
+    },
+    Key, XChaCha20Poly1305, XNonce,
+};
+use eyre::{bail, ensure, eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+use crate::util::ContextExt;
+
+/// Plaintext bytes per encrypted chunk of a stream.
+const CHUNK_SIZE: usize = 1 << 16;
+const TAG_SIZE: usize = 16;
+/// The XChaCha20 nonce, minus the 5 bytes the stream construction uses for its counter.
+const STREAM_NONCE_SIZE: usize = 19;
+const SALT_SIZE: usize = 16;
+const PASSPHRASE_VAR: &str = "VAULT_PASSPHRASE";
+
+/// What the keys of an encrypted vault are unlocked with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    Passphrase,
+    KeyFile,
+}
+
+/// The vault keys, encrypted with a key derived from the passphrase or key file with Argon2id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WrappedKeys {
+    pub source: KeySource,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// The keys of an unlocked vault. Blobs and the database are encrypted with `data_key`, and blobs
+/// are named by a hash keyed with `name_key`, so their names don't give away what files are stored.
+#[derive(Clone)]
+pub struct Cipher {
+    data_key: Key,
+    name_key: [u8; 32],
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+impl Cipher {
+    pub fn generate() -> Self {
+        let mut name_key = [0; 32];
+        OsRng.fill_bytes(&mut name_key);
+        Self {
+            data_key: XChaCha20Poly1305::generate_key(&mut OsRng),
+            name_key,
+        }
+    }
+
+    pub fn wrap(&self, source: KeySource, secret: &[u8]) -> Result<WrappedKeys> {
+        let mut salt = vec![0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut keys = self.data_key.to_vec();
+        keys.extend_from_slice(&self.name_key);
+        let ciphertext = wrapping_cipher(secret, &salt)?
+            .encrypt(&nonce, keys.as_slice())
+            .map_err(|_| eyre!("encrypting vault keys"))?;
+        Ok(WrappedKeys {
+            source,
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    pub fn unwrap(wrapped: &WrappedKeys, secret: &[u8]) -> Result<Self> {
+        ensure!(
+            wrapped.nonce.len() == 24,
+            "vault config has an invalid key nonce"
+        );
+        let keys = wrapping_cipher(secret, &wrapped.salt)?
+            .decrypt(
+                XNonce::from_slice(&wrapped.nonce),
+                wrapped.ciphertext.as_slice(),
+            )
+            .map_err(|_| match wrapped.source {
+                KeySource::Passphrase => eyre!("wrong passphrase"),
+                KeySource::KeyFile => eyre!("wrong key file"),
+            })?;
+        ensure!(keys.len() == 64, "vault config has invalid keys");
+        let (data_key, name_key) = keys.split_at(32);
+        Ok(Self {
+            data_key: *Key::from_slice(data_key),
+            name_key: name_key.try_into().unwrap(),
+        })
+    }
+
+    pub fn hasher(&self) -> blake3::Hasher {
+        blake3::Hasher::new_keyed(&self.name_key)
+    }
+
+    /// Starts an encrypted stream by writing its nonce to writer.
+    pub fn encrypt_writer<W: Write>(&self, mut writer: W) -> io::Result<EncryptWriter<W>> {
+        let mut nonce = [0; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        writer.write_all(&nonce)?;
+        let aead = XChaCha20Poly1305::new(&self.data_key);
+        Ok(EncryptWriter {
+            inner: writer,
+            encryptor: EncryptorBE32::from_aead(aead, (&nonce).into()),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    /// Reads the nonce at the start of an encrypted stream.
+    pub fn decrypt_reader<R: Read>(&self, reader: R) -> io::Result<DecryptReader<R>> {
+        let mut reader = BufReader::new(reader);
+        let mut nonce = [0; STREAM_NONCE_SIZE];
+        reader.read_exact(&mut nonce)?;
+        let aead = XChaCha20Poly1305::new(&self.data_key);
+        Ok(DecryptReader {
+            inner: reader,
+            decryptor: Some(DecryptorBE32::from_aead(aead, (&nonce).into())),
+            chunk: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut writer = self.encrypt_writer(Vec::new())?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        self.decrypt_reader(data)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+}
+
+fn wrapping_cipher(secret: &[u8], salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| eyre!("deriving key: {e}"))?;
+    Ok(XChaCha20Poly1305::new(&key))
+}
+
+/// Reads the passphrase or key file the vault keys are wrapped with.
+/// The passphrase is taken from VAULT_PASSPHRASE if it is set, otherwise it is asked for,
+/// twice if `confirm` is set.
+pub fn read_secret(source: KeySource, key_file: Option<&Path>, confirm: bool) -> Result<Vec<u8>> {
+    match source {
+        KeySource::KeyFile => {
+            let Some(key_file) = key_file else {
+                bail!("the vault is encrypted with a key file, pass it with --key-file");
+            };
+            let key = fs::read(key_file).context_2("reading key file", key_file)?;
+            ensure!(!key.is_empty(), "key file {} is empty", key_file.display());
+            Ok(key)
+        }
+        KeySource::Passphrase => {
+            if let Some(passphrase) = std::env::var_os(PASSPHRASE_VAR) {
+                return Ok(passphrase.into_encoded_bytes());
+            }
+            let passphrase = rpassword::prompt_password("Vault passphrase: ")?;
+            ensure!(!passphrase.is_empty(), "the passphrase is empty");
+            if confirm {
+                let again = rpassword::prompt_password("Repeat passphrase: ")?;
+                ensure!(passphrase == again, "the passphrases don't match");
+            }
+            Ok(passphrase.into_bytes())
+        }
+    }
+}
+
+/// Encrypts everything written to it in chunks, each authenticated on its own, with the last
+/// one marked as such so that truncation is detected. `finish` must be called at the end.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Writes the last chunk and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        let Self {
+            mut inner,
+            encryptor,
+            buf,
+        } = self;
+        let chunk = encryptor
+            .encrypt_last(buf.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        inner.write_all(&chunk)?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // A full chunk is only written once more data follows, since the last one is special.
+        if self.buf.len() == CHUNK_SIZE {
+            let chunk = self
+                .encryptor
+                .encrypt_next(self.buf.as_slice())
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&chunk)?;
+            self.buf.clear();
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptWriter`, failing if it was modified or truncated.
+pub struct DecryptReader<R> {
+    inner: BufReader<R>,
+    /// None once the last chunk has been decrypted
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    chunk: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        self.chunk.clear();
+        (&mut self.inner)
+            .take((CHUNK_SIZE + TAG_SIZE) as u64)
+            .read_to_end(&mut self.chunk)?;
+        let is_last = self.inner.fill_buf()?.is_empty();
+        let plain = if is_last {
+            let decryptor = self.decryptor.take().unwrap();
+            decryptor.decrypt_last(self.chunk.as_slice())
+        } else {
+            let decryptor = self.decryptor.as_mut().unwrap();
+            decryptor.decrypt_next(self.chunk.as_slice())
+        };
+        self.plain = plain.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decryption failed, the data is corrupt or the wrong key was used",
+            )
+        })?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::generate();
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = cipher.encrypt(&data).unwrap();
+            assert_eq!(cipher.decrypt(&encrypted).unwrap(), data, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let cipher = Cipher::generate();
+        let data = vec![7; CHUNK_SIZE + 1];
+        let encrypted = cipher.encrypt(&data).unwrap();
+
+        // Dropping the last chunk leaves a stream that ends in a chunk not marked as last
+        let truncated = &encrypted[..STREAM_NONCE_SIZE + CHUNK_SIZE + TAG_SIZE];
+        assert!(cipher.decrypt(truncated).is_err());
+        assert!(cipher.decrypt(&encrypted[..encrypted.len() - 1]).is_err());
+
+        let mut flipped = encrypted.clone();
+        flipped[STREAM_NONCE_SIZE + 10] ^= 1;
+        assert!(cipher.decrypt(&flipped).is_err());
+
+        assert!(Cipher::generate().decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn wrapped_keys() {
+        let cipher = Cipher::generate();
+        let wrapped = cipher.wrap(KeySource::Passphrase, b"right").unwrap();
+        let unwrapped = Cipher::unwrap(&wrapped, b"right").unwrap();
+        assert_eq!(unwrapped.data_key, cipher.data_key);
+        assert_eq!(unwrapped.name_key, cipher.name_key);
+
+        let e = Cipher::unwrap(&wrapped, b"wrong").unwrap_err();
+        assert_eq!(e.to_string(), "wrong passphrase");
+    }
+}
@@ -1,47 +1,52 @@
-use super::backup::Backup;
-use crate::util::ContextExt;
+use super::{backend::Backend, backup::Backup, crypto::Cipher};
 
-use eyre::Result;
+use eyre::{Context, ContextCompat, Result};
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::BTreeMap,
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::{Path, PathBuf},
-};
+use std::{collections::BTreeMap, io::BufReader};
 
-const DATABASE_NAME: &str = "database.json";
+pub(super) const DATABASE_KEY: &str = "database.json";
 
 // TODO: refactor into Database and DatabaseInner where DatabaseInner only contains serialized data
-// and Database contains runtime metadata like the path to read/write data
+// and Database contains runtime metadata like the cipher
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Database {
+    /// Set in encrypted vaults, where the whole file is encrypted
     #[serde(skip)]
-    path: PathBuf,
+    cipher: Option<Cipher>,
     backups: BTreeMap<String, Backup>,
 }
 
 impl Database {
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        let path = path.as_ref().join(DATABASE_NAME);
+    pub fn new(cipher: Option<Cipher>) -> Self {
         Self {
-            path,
+            cipher,
             backups: BTreeMap::new(),
         }
     }
 
-    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref().join(DATABASE_NAME);
-        let f = BufReader::new(File::open(&path).context_2("reading db file", &path)?);
-        let mut db: Database = serde_json::from_reader(f)?;
-        db.path = path;
+    pub fn load(backend: &dyn Backend, cipher: Option<Cipher>) -> Result<Self> {
+        let object = backend
+            .get(DATABASE_KEY)?
+            .with_context(|| format!("{DATABASE_KEY} does not exist"))?;
+        let mut db: Database = match &cipher {
+            Some(cipher) => {
+                let object = cipher
+                    .decrypt_reader(BufReader::new(object))
+                    .context("decrypting db file")?;
+                serde_json::from_reader(object).context("parsing db file")?
+            }
+            None => serde_json::from_reader(BufReader::new(object)).context("parsing db file")?,
+        };
+        db.cipher = cipher;
         Ok(db)
     }
 
-    pub fn write(&self) -> Result<()> {
-        let f = BufWriter::new(File::create(&self.path).context_2("writing db file", &self.path)?);
-        serde_json::to_writer_pretty(f, self)?;
-        Ok(())
+    pub fn write(&self, backend: &dyn Backend) -> Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        if let Some(cipher) = &self.cipher {
+            data = cipher.encrypt(&data)?;
+        }
+        backend.put(DATABASE_KEY, &data).context("writing db file")
     }
 
     pub fn iter_backups(&self) -> std::collections::btree_map::Iter<'_, String, Backup> {
@@ -7,7 +7,7 @@ use std::{
 use inotify::{Inotify, WatchMask};
 use thiserror::Error;
 
-const LOCKFILE_NAME: &str = "lock";
+pub(super) const LOCKFILE_NAME: &str = "lock";
 
 #[derive(Debug)]
 pub struct DirectoryLock(PathBuf);
@@ -0,0 +1,255 @@
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{BufRead, BufReader, Cursor, Read},
+};
+
+use super::backend::Backend;
+use crate::util::Hash;
+
+pub(super) const PACKS_PREFIX: &str = "packs/";
+pub(super) const PACK_SUFFIX: &str = ".pack";
+const INDEX_SUFFIX: &str = ".idx";
+/// A pack is stored once it is this large.
+const PACK_TARGET_SIZE: usize = 32 << 20;
+
+/// Where a blob is inside a pack.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PackEntry {
+    pub hash: Hash,
+    pub pack: u64,
+    pub offset: u64,
+    pub len: u64,
+    /// Whether the stored bytes are zstd compressed
+    pub compressed: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RepackStats {
+    pub packs_rewritten: usize,
+    pub blobs_dropped: usize,
+    pub bytes_freed: u64,
+}
+
+/// Small blobs, collected into a few large pack objects instead of getting an object each.
+///
+/// A pack is filled in memory and stored once it is full, or when storage is flushed.
+/// Each pack has an index object with a JSON line per blob, which is stored after the pack,
+/// so a pack without an index was never finished and is ignored. A pack whose index can't be
+/// read is ignored too, but kept, so that `verify` can report it. Packs are never modified,
+/// `repack` writes new ones without the blobs that are no longer used.
+#[derive(Debug)]
+pub struct Packs {
+    index: HashMap<Hash, PackEntry>,
+    /// The ids of all stored packs that have an index
+    indexed: BTreeSet<u64>,
+    /// The ids of the packs whose index can't be read
+    unreadable: BTreeSet<u64>,
+    /// The contents of the pack being filled, whose id is `next_id`
+    pending: Vec<u8>,
+    pending_entries: Vec<PackEntry>,
+    next_id: u64,
+}
+
+impl Packs {
+    pub fn load(backend: &dyn Backend) -> Result<Self> {
+        let mut index = HashMap::new();
+        let mut indexed = BTreeSet::new();
+        let mut unreadable = BTreeSet::new();
+        let mut max_id = None;
+        for key in backend.list(PACKS_PREFIX)? {
+            let Some((id, suffix)) = parse_key(&key) else {
+                continue;
+            };
+            max_id = max_id.max(Some(id));
+            if suffix != INDEX_SUFFIX {
+                continue;
+            }
+            let Some(object) = backend.get(&key)? else {
+                continue;
+            };
+            match read_index(object, &key) {
+                Ok(entries) => {
+                    index.extend(entries.into_iter().map(|entry| (entry.hash, entry)));
+                    indexed.insert(id);
+                }
+                // Failing here would keep verify and repair from opening the vault
+                Err(e) => {
+                    eprintln!("[warning] {e:#}. Ignoring the blobs in {}.", pack_key(id));
+                    unreadable.insert(id);
+                }
+            }
+        }
+        Ok(Self {
+            index,
+            indexed,
+            unreadable,
+            pending: Vec::new(),
+            pending_entries: Vec::new(),
+            next_id: max_id.map_or(0, |id| id + 1),
+        })
+    }
+
+    pub fn get(&self, hash: Hash) -> Option<PackEntry> {
+        self.index.get(&hash).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PackEntry> {
+        self.index.values()
+    }
+
+    /// The ids of the packs whose index can't be read.
+    pub fn unreadable(&self) -> &BTreeSet<u64> {
+        &self.unreadable
+    }
+
+    /// Forgets a pack whose index can't be read, once it has been moved away.
+    pub fn remove_unreadable(&mut self, id: u64) {
+        self.unreadable.remove(&id);
+    }
+
+    /// Opens the stored bytes of a packed blob.
+    pub fn open(&self, backend: &dyn Backend, entry: &PackEntry) -> Result<Box<dyn Read + Send>> {
+        if entry.pack == self.next_id {
+            let start = entry.offset as usize;
+            let blob = self.pending[start..start + entry.len as usize].to_vec();
+            return Ok(Box::new(Cursor::new(blob)));
+        }
+        backend.get_range(&pack_key(entry.pack), entry.offset, entry.len)
+    }
+
+    /// Adds the stored bytes of a blob to the pending pack, storing it if it is full.
+    pub fn add(
+        &mut self,
+        backend: &dyn Backend,
+        hash: Hash,
+        blob: &[u8],
+        compressed: bool,
+    ) -> Result<()> {
+        let entry = PackEntry {
+            hash,
+            pack: self.next_id,
+            offset: self.pending.len() as u64,
+            len: blob.len() as u64,
+            compressed,
+        };
+        self.pending.extend_from_slice(blob);
+        self.pending_entries.push(entry);
+        self.index.insert(hash, entry);
+        if self.pending.len() >= PACK_TARGET_SIZE {
+            self.flush(backend)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the pending pack, followed by its index.
+    pub fn flush(&mut self, backend: &dyn Backend) -> Result<()> {
+        if self.pending_entries.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_id;
+        let mut index = Vec::new();
+        for entry in &self.pending_entries {
+            serde_json::to_writer(&mut index, entry)?;
+            index.push(b'\n');
+        }
+        backend.put(&pack_key(id), &self.pending)?;
+        backend.put(&index_key(id), &index)?;
+        self.indexed.insert(id);
+        self.pending.clear();
+        self.pending_entries.clear();
+        self.next_id += 1;
+        Ok(())
+    }
+
+    /// Drops the packed blobs that aren't in `live`. The packs that hold any are replaced by
+    /// new ones with only their live blobs. Packs without an index, left behind by an
+    /// interrupted flush, are deleted too.
+    pub fn repack(&mut self, backend: &dyn Backend, live: &HashSet<Hash>) -> Result<RepackStats> {
+        self.flush(backend)?;
+
+        let mut stats = RepackStats::default();
+        let mut by_pack: HashMap<u64, Vec<PackEntry>> = HashMap::new();
+        for entry in self.index.values() {
+            by_pack.entry(entry.pack).or_default().push(*entry);
+        }
+        // Packs whose blobs were all copied elsewhere by an interrupted repack
+        let mut rewrite: BTreeSet<u64> = self
+            .indexed
+            .iter()
+            .filter(|id| !by_pack.contains_key(id))
+            .copied()
+            .collect();
+        for (&pack, entries) in &by_pack {
+            let dead: Vec<_> = entries.iter().filter(|e| !live.contains(&e.hash)).collect();
+            if !dead.is_empty() {
+                stats.blobs_dropped += dead.len();
+                stats.bytes_freed += dead.iter().map(|e| e.len).sum::<u64>();
+                rewrite.insert(pack);
+            }
+        }
+
+        for &pack in &rewrite {
+            for entry in by_pack.get(&pack).into_iter().flatten() {
+                if !live.contains(&entry.hash) {
+                    self.index.remove(&entry.hash);
+                    continue;
+                }
+                let mut blob = Vec::with_capacity(entry.len as usize);
+                self.open(backend, entry)?
+                    .read_to_end(&mut blob)
+                    .with_context(|| format!("reading {}", pack_key(pack)))?;
+                self.add(backend, entry.hash, &blob, entry.compressed)?;
+            }
+        }
+        // The new packs must be stored before the old ones are gone
+        self.flush(backend)?;
+        for &pack in &rewrite {
+            backend.delete(&index_key(pack))?;
+            backend.delete(&pack_key(pack))?;
+            self.indexed.remove(&pack);
+        }
+        stats.packs_rewritten = rewrite.len();
+
+        for key in backend.list(PACKS_PREFIX)? {
+            match parse_key(&key) {
+                Some((id, PACK_SUFFIX))
+                    if !self.indexed.contains(&id) && !self.unreadable.contains(&id) =>
+                {
+                    backend.delete(&key)?
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
+
+pub(super) fn pack_key(id: u64) -> String {
+    format!("{PACKS_PREFIX}{id:08}{PACK_SUFFIX}")
+}
+
+pub(super) fn index_key(id: u64) -> String {
+    format!("{PACKS_PREFIX}{id:08}{INDEX_SUFFIX}")
+}
+
+/// Parses an index object, a JSON line per blob.
+fn read_index(object: impl Read, key: &str) -> Result<Vec<PackEntry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(object).lines() {
+        let line = line.with_context(|| format!("reading {key}"))?;
+        entries.push(serde_json::from_str(&line).with_context(|| format!("parsing {key}"))?);
+    }
+    Ok(entries)
+}
+
+/// Splits the key of a pack or index into its id and suffix.
+fn parse_key(key: &str) -> Option<(u64, &'static str)> {
+    let name = key.strip_prefix(PACKS_PREFIX)?;
+    let (id, suffix) = name.split_at(name.find('.')?);
+    let suffix = [PACK_SUFFIX, INDEX_SUFFIX]
+        .into_iter()
+        .find(|&s| s == suffix)?;
+    Some((id.parse().ok()?, suffix))
+}
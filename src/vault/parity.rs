@@ -0,0 +1,244 @@
+use eyre::{ensure, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{io::Read, path::PathBuf};
+
+use super::{
+    backend::Backend,
+    pack::{PACKS_PREFIX, PACK_SUFFIX},
+    storage::DATA_PREFIX,
+};
+
+pub const PARITY_PREFIX: &str = "parity/";
+const PARITY_SUFFIX: &str = ".par";
+const MAGIC: &[u8; 8] = b"SFHPAR01";
+/// Objects are split into at most this many shards, so large ones get large shards.
+const MAX_DATA_SHARDS: usize = 128;
+const MIN_SHARD_SIZE: usize = 4 << 10;
+const HASH_LEN: usize = blake3::OUT_LEN;
+
+/// Whether objects with this key get parity: blobs with an object of their own, and packs.
+pub fn is_protected(key: &str) -> bool {
+    key.starts_with(DATA_PREFIX) || (key.starts_with(PACKS_PREFIX) && key.ends_with(PACK_SUFFIX))
+}
+
+/// The key of the parity of an object.
+pub fn parity_key(key: &str) -> String {
+    format!("{PARITY_PREFIX}{key}{PARITY_SUFFIX}")
+}
+
+/// The key of the object a parity object belongs to.
+pub fn object_key(parity_key: &str) -> Option<&str> {
+    parity_key
+        .strip_prefix(PARITY_PREFIX)?
+        .strip_suffix(PARITY_SUFFIX)
+}
+
+/// How an object is split into shards, and the hash of every shard, data shards first.
+struct Header {
+    len: u64,
+    data_shards: usize,
+    parity_shards: usize,
+    shard_size: usize,
+    hashes: Vec<blake3::Hash>,
+}
+
+impl Header {
+    /// Written as the magic, the length, the shard counts and size, the shard hashes,
+    /// and a hash of all that, so that a damaged header isn't mistaken for a valid one.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&(self.data_shards as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.parity_shards as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.shard_size as u64).to_le_bytes());
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        let checksum = blake3::hash(&bytes);
+        bytes.extend_from_slice(checksum.as_bytes());
+        bytes
+    }
+
+    /// Parses a header, returning it and the parity shards that follow it.
+    fn parse(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        const FIXED_LEN: usize = 8 + 8 + 4 + 4 + 8;
+        ensure!(
+            bytes.len() >= FIXED_LEN && bytes.starts_with(MAGIC),
+            "not a parity object"
+        );
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let data_shards = u32_at(16) as usize;
+        let parity_shards = u32_at(20) as usize;
+        let header_len = FIXED_LEN + (data_shards + parity_shards + 1) * HASH_LEN;
+        ensure!(bytes.len() >= header_len, "parity header is truncated");
+        let (header, rest) = bytes.split_at(header_len);
+        let (fields, checksum) = header.split_at(header_len - HASH_LEN);
+        ensure!(
+            blake3::hash(fields).as_bytes() == checksum,
+            "parity header is damaged"
+        );
+        let hashes = fields[FIXED_LEN..]
+            .chunks(HASH_LEN)
+            .map(|hash| blake3::Hash::from_bytes(hash.try_into().unwrap()))
+            .collect();
+        let header = Self {
+            len: u64_at(8),
+            data_shards,
+            parity_shards,
+            shard_size: u64_at(24) as usize,
+            hashes,
+        };
+        Ok((header, rest))
+    }
+}
+
+/// Splits data into `n` shards of `shard_size` bytes, padding the last ones with zeros.
+fn split(data: &[u8], n: usize, shard_size: usize) -> Vec<Vec<u8>> {
+    (0..n)
+        .map(|i| {
+            let start = (i * shard_size).min(data.len());
+            let end = (start + shard_size).min(data.len());
+            let mut shard = data[start..end].to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect()
+}
+
+/// Computes the parity object of data, with parity shards adding up to `percent` of its size,
+/// rounded up to at least one shard.
+pub fn encode(data: &[u8], percent: u8) -> Result<Vec<u8>> {
+    let shard_size = data.len().div_ceil(MAX_DATA_SHARDS).max(MIN_SHARD_SIZE);
+    let data_shards = data.len().div_ceil(shard_size).max(1);
+    let parity_shards = (data_shards * percent as usize).div_ceil(100).max(1);
+    let mut shards = split(data, data_shards, shard_size);
+    shards.resize(data_shards + parity_shards, vec![0; shard_size]);
+    ReedSolomon::new(data_shards, parity_shards)?.encode(&mut shards)?;
+
+    let header = Header {
+        len: data.len() as u64,
+        data_shards,
+        parity_shards,
+        shard_size,
+        hashes: shards.iter().map(|shard| blake3::hash(shard)).collect(),
+    };
+    let mut bytes = header.to_bytes();
+    for shard in &shards[data_shards..] {
+        bytes.extend_from_slice(shard);
+    }
+    Ok(bytes)
+}
+
+pub enum Recovered {
+    /// The object is the same as when its parity was computed
+    Intact,
+    /// The object as it was when its parity was computed
+    Rebuilt(Vec<u8>),
+}
+
+/// Checks an object against its parity, and rebuilds it if it is damaged. A missing object
+/// can be passed as empty, it is only rebuilt if there are as many parity shards as data
+/// shards. Fails if more shards are damaged than there are parity shards.
+pub fn recover(object: &[u8], parity: &[u8]) -> Result<Recovered> {
+    let (header, parity) = Header::parse(parity)?;
+    let Header {
+        data_shards,
+        parity_shards,
+        shard_size,
+        ..
+    } = header;
+    let mut shards: Vec<Option<Vec<u8>>> = split(object, data_shards, shard_size)
+        .into_iter()
+        .map(Some)
+        .collect();
+    shards.extend((0..parity_shards).map(|i| {
+        parity
+            .get(i * shard_size..(i + 1) * shard_size)
+            .map(<[u8]>::to_vec)
+    }));
+    for (shard, hash) in shards.iter_mut().zip(&header.hashes) {
+        if shard.as_deref().is_some_and(|s| blake3::hash(s) != *hash) {
+            *shard = None;
+        }
+    }
+
+    let damaged = shards[..data_shards].iter().filter(|s| s.is_none()).count();
+    if damaged == 0 && object.len() as u64 == header.len {
+        return Ok(Recovered::Intact);
+    }
+    let lost = shards.iter().filter(|s| s.is_none()).count();
+    ensure!(
+        lost <= parity_shards,
+        "{lost} of {} shards are damaged, parity can only rebuild {parity_shards}",
+        shards.len()
+    );
+    if damaged > 0 {
+        ReedSolomon::new(data_shards, parity_shards)?.reconstruct_data(&mut shards)?;
+    }
+    let mut data: Vec<u8> = shards[..data_shards]
+        .iter()
+        .flat_map(|shard| shard.as_deref().unwrap())
+        .copied()
+        .collect();
+    data.truncate(header.len as usize);
+    Ok(Recovered::Rebuilt(data))
+}
+
+/// Stores parity beside every protected object that is put, and deletes it along with
+/// the object. Used for vaults that have parity turned on.
+#[derive(Debug)]
+pub struct ParityBackend {
+    inner: Box<dyn Backend>,
+    percent: u8,
+}
+
+impl ParityBackend {
+    pub fn new(inner: Box<dyn Backend>, percent: u8) -> Self {
+        Self { inner, percent }
+    }
+}
+
+impl Backend for ParityBackend {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.inner.put(key, data)?;
+        if is_protected(key) {
+            // An object without parity, if this is interrupted, gets it from `add_parity`
+            self.inner
+                .put(&parity_key(key), &encode(data, self.percent)?)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key)?;
+        if is_protected(key) {
+            self.inner.delete(&parity_key(key))?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        self.inner.local_path(key)
+    }
+
+    fn replicas(&self) -> Vec<&dyn Backend> {
+        self.inner.replicas()
+    }
+}
@@ -1,80 +1,773 @@
-use eyre::{Context, Result};
+use eyre::{ensure, Context, ContextCompat, Result};
+use fastcdc::v2020::StreamCDC;
 use std::{
-    fs,
+    borrow::Cow,
+    collections::HashSet,
+    fs::File,
+    io::{self, Cursor, Read},
     path::{Path, PathBuf},
+    sync::Mutex,
 };
-use walkdir::WalkDir;
 
-use crate::util::{ContextExt, Hash};
+use super::{
+    backend::Backend,
+    config::Config,
+    crypto::Cipher,
+    pack::{self, Packs, RepackStats, PACKS_PREFIX},
+    parity::{self, ParityBackend, Recovered, PARITY_PREFIX},
+};
+use crate::{
+    progress::Progress,
+    util::{ContextExt, Hash},
+};
+
+pub(super) const DATA_PREFIX: &str = "data/";
+const COMPRESSED_SUFFIX: &str = ".zst";
+pub(super) const QUARANTINE_PREFIX: &str = "quarantine/";
+const COPY_BUF_SIZE: usize = 1 << 16;
+/// Blobs smaller than this, as stored, go into packs instead of getting an object of their own.
+const MAX_PACKED_BLOB_SIZE: usize = 128 << 10;
 
-const DATA_DIR_NAME: &str = "data";
+/// Files at least this large are split into content-defined chunks, so that changing part of a
+/// large file only costs the chunks around the change.
+const CHUNKING_THRESHOLD: u64 = 8 << 20;
+const MIN_CHUNK_SIZE: u32 = 512 << 10;
+const AVG_CHUNK_SIZE: u32 = 1 << 20;
+const MAX_CHUNK_SIZE: u32 = 4 << 20;
+
+/// Where the contents of a file are in storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// The hash of the whole file. Unless it is chunked, this is the blob that holds it.
+    pub hash: Hash,
+    /// The blobs that make up a chunked file, in order. Empty if it isn't chunked.
+    pub chunks: Vec<Hash>,
+}
 
+/// The blobs a file with this hash and these chunks is stored in.
+pub fn blobs<'a>(hash: &'a Hash, chunks: &'a [Hash]) -> &'a [Hash] {
+    if chunks.is_empty() {
+        std::slice::from_ref(hash)
+    } else {
+        chunks
+    }
+}
+
+/// Content-addressed blobs, kept in a backend. Blobs are compressed and encrypted here,
+/// depending on the vault config, so the backend only ever sees the stored bytes.
 #[derive(Debug)]
 pub struct Storage {
-    data_dir: PathBuf,
+    backend: Box<dyn Backend>,
+    compression_level: Option<i32>,
+    cipher: Option<Cipher>,
+    packs: Mutex<Packs>,
 }
 
 impl Storage {
-    pub fn new(vault_dir: impl AsRef<Path>) -> Self {
-        Self {
-            data_dir: vault_dir.as_ref().join(DATA_DIR_NAME),
-        }
+    pub fn new(backend: Box<dyn Backend>, config: &Config, cipher: Option<Cipher>) -> Result<Self> {
+        let backend: Box<dyn Backend> = match config.parity {
+            Some(percent) => Box::new(ParityBackend::new(backend, percent)),
+            None => backend,
+        };
+        let packs = Packs::load(&*backend).context("loading pack index")?;
+        Ok(Self {
+            backend,
+            compression_level: config.compression_level,
+            cipher,
+            packs: Mutex::new(packs),
+        })
     }
 
-    pub fn path_of(&self, hash: Hash) -> PathBuf {
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    /// The key of the blob if it is stored uncompressed.
+    fn key_of(hash: Hash) -> String {
         let hex = hash.inner().to_hex();
-        let first_hex_byte = hex.split_at(2).0;
-        let mut path = self.data_dir.clone();
-        path.push(first_hex_byte);
-        path.push(hex.as_str());
-        path
+        format!("{DATA_PREFIX}{}/{hex}", &hex[..2])
     }
 
-    pub fn insert_file(&self, source: &Path, hash: Hash) -> Result<()> {
-        let dest = self.path_of(hash);
+    /// The key of the blob if it is stored compressed.
+    fn compressed_key_of(hash: Hash) -> String {
+        Self::key_of(hash) + COMPRESSED_SUFFIX
+    }
+
+    /// Returns the path of the blob if it is a local file that holds the contents as they are,
+    /// uncompressed and unencrypted, so that it can be used directly.
+    pub fn raw_path_of(&self, hash: Hash) -> Option<PathBuf> {
+        if self.cipher.is_some() {
+            return None;
+        }
+        self.backend.local_path(&Self::key_of(hash))
+    }
+
+    pub fn contains(&self, hash: Hash) -> Result<bool> {
+        if self.packs.lock().unwrap().get(hash).is_some() {
+            return Ok(true);
+        }
+        for key in [Self::key_of(hash), Self::compressed_key_of(hash)] {
+            if self.backend.exists(&key)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Opens a blob for reading its original contents, decrypting and decompressing it if needed.
+    /// Reading fails at the end if the contents don't match the hash, so that a blob can't be
+    /// swapped for another one. In a mirrored vault each copy is read and checked before it is
+    /// used, so that a damaged one can be skipped.
+    pub fn open_blob(&self, hash: Hash) -> Result<Box<dyn Read>> {
+        let replicas = self.backend.replicas();
+        if replicas.is_empty() {
+            let blob = self.open_blob_in(&*self.backend, hash)?;
+            return Ok(Box::new(VerifyReader {
+                inner: blob,
+                hasher: self.hasher(),
+                hash,
+            }));
+        }
+        let mut error = None;
+        for (i, &replica) in replicas.iter().enumerate() {
+            match self.read_blob_in(replica, hash) {
+                Ok(data) => return Ok(Box::new(Cursor::new(data))),
+                Err(e) => {
+                    eprintln!("[warning] copy {} of {}: {e:#}", i + 1, replicas.len());
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.unwrap())
+    }
+
+    fn open_blob_in(&self, backend: &dyn Backend, hash: Hash) -> Result<Box<dyn Read>> {
+        let key = Self::key_of(hash);
+        if let Some(object) = backend.get(&key)? {
+            return self
+                .blob_reader(object)
+                .with_context(|| format!("open {key}"));
+        }
+        let key = Self::compressed_key_of(hash);
+        if let Some(object) = backend.get(&key)? {
+            let object = self
+                .blob_reader(object)
+                .with_context(|| format!("open {key}"))?;
+            let decoder = zstd::Decoder::new(object).with_context(|| format!("zstd {key}"))?;
+            return Ok(Box::new(decoder));
+        }
+        self.open_packed(backend, hash)
+    }
+
+    fn open_packed(&self, backend: &dyn Backend, hash: Hash) -> Result<Box<dyn Read>> {
+        let packs = self.packs.lock().unwrap();
+        let entry = packs
+            .get(hash)
+            .with_context(|| format!("blob {hash} is missing from storage"))?;
+        let object = self.blob_reader(packs.open(backend, &entry)?)?;
+        if entry.compressed {
+            Ok(Box::new(zstd::Decoder::new(object)?))
+        } else {
+            Ok(object)
+        }
+    }
+
+    /// Reads a whole blob from backend, and checks it against its hash.
+    fn read_blob_in(&self, backend: &dyn Backend, hash: Hash) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_blob_in(backend, hash)?
+            .read_to_end(&mut data)
+            .with_context(|| format!("reading blob {hash}"))?;
+        ensure!(self.hash(&data) == hash, "blob {hash} is corrupted");
+        Ok(data)
+    }
+
+    /// The key of the object that holds a blob in backend.
+    fn object_key_in(&self, backend: &dyn Backend, hash: Hash) -> Result<String> {
+        for key in [Self::key_of(hash), Self::compressed_key_of(hash)] {
+            if backend.exists(&key)? {
+                return Ok(key);
+            }
+        }
+        let packs = self.packs.lock().unwrap();
+        let entry = packs
+            .get(hash)
+            .with_context(|| format!("blob {hash} is missing from storage"))?;
+        Ok(pack::pack_key(entry.pack))
+    }
 
-        if dest.try_exists().context_2("stat", &dest)? {
+    /// Rehashes every blob in each copy of a mirrored vault. Where a copy is damaged, the
+    /// object holding it is replaced with the one from a copy where the blob is intact.
+    /// Returns how many objects were replaced, and the blobs that are damaged in every copy.
+    pub fn verify_replicas(&self, progress: &mut dyn Progress) -> Result<(usize, Vec<Hash>)> {
+        let mut blobs = self.list_blobs()?;
+        blobs.sort_unstable_by_key(|hash| *hash.inner().as_bytes());
+        blobs.dedup();
+
+        progress.expect(blobs.len() as u64, 0);
+        let mut replaced = 0;
+        let mut lost = Vec::new();
+        for hash in blobs {
+            let copies = self.rehash_copies(hash, progress);
+            let Some(intact) = copies
+                .iter()
+                .position(|copy| copy.as_ref().ok() == Some(&hash))
+            else {
+                lost.push(hash);
+                continue;
+            };
+            for (i, copy) in copies.iter().enumerate() {
+                match copy {
+                    Ok(actual) if *actual == hash => continue,
+                    Ok(actual) => eprintln!(
+                        "[warning] copy {} of {}: blob {hash} has the contents of {actual}",
+                        i + 1,
+                        copies.len()
+                    ),
+                    Err(e) => eprintln!("[warning] copy {} of {}: {e:#}", i + 1, copies.len()),
+                }
+                self.replace_copy(hash, i, intact, &mut HashSet::new())?;
+                replaced += 1;
+            }
+        }
+        progress.finish();
+        Ok((replaced, lost))
+    }
+
+    /// Replaces the object holding a blob in copy `damaged` of a mirrored vault with the one
+    /// from copy `intact`, leaving the other copies alone. Objects in `rewritten` are skipped,
+    /// and the ones that are replaced are added to it, so that a pack holding several damaged
+    /// blobs is only rewritten once.
+    pub fn replace_copy(
+        &self,
+        hash: Hash,
+        damaged: usize,
+        intact: usize,
+        rewritten: &mut HashSet<(usize, String)>,
+    ) -> Result<()> {
+        let replicas = self.backend.replicas();
+        let (Some(&to), Some(&from)) = (replicas.get(damaged), replicas.get(intact)) else {
+            eyre::bail!("this vault has no copy {}", damaged.max(intact) + 1);
+        };
+        let key = self.object_key_in(from, hash)?;
+        if rewritten.contains(&(damaged, key.clone())) {
             return Ok(());
         }
+        let object = read_object(from, &key)?.with_context(|| format!("{key} is missing"))?;
+        to.put(&key, &object)?;
+        rewritten.insert((damaged, key));
+        Ok(())
+    }
+
+    /// Where the object holding a blob is in a copy of a mirrored vault, if it is there and
+    /// is a local file.
+    pub fn path_in_copy(&self, hash: Hash, copy: usize) -> Option<PathBuf> {
+        let replica = *self.backend.replicas().get(copy)?;
+        replica.local_path(&self.object_key_in(replica, hash).ok()?)
+    }
+
+    /// Copies the objects holding the blobs in `live` that the first copy of a mirrored vault
+    /// lost back to it from another copy, along with their parity. Must be done before the
+    /// mirrors are resynced from the first copy, which would delete them. Returns how many
+    /// objects were copied.
+    pub fn restore_primary(
+        &self,
+        live: &HashSet<Hash>,
+        progress: &mut dyn Progress,
+    ) -> Result<usize> {
+        let replicas = self.backend.replicas();
+        let Some((&primary, mirrors)) = replicas.split_first() else {
+            return Ok(0);
+        };
+        let mut present: HashSet<String> = primary.list("")?.into_iter().collect();
+        let mut copied = 0;
+        for &hash in live {
+            let loose = [Self::key_of(hash), Self::compressed_key_of(hash)];
+            if loose.iter().any(|key| present.contains(key)) {
+                continue;
+            }
+            let mut keys = match self.packs.lock().unwrap().get(hash) {
+                Some(entry) => vec![pack::pack_key(entry.pack), pack::index_key(entry.pack)],
+                None => loose.to_vec(),
+            };
+            if keys.iter().all(|key| present.contains(key)) {
+                continue;
+            }
+            let parity: Vec<_> = keys.iter().map(|key| parity::parity_key(key)).collect();
+            keys.extend(parity);
+            for key in keys {
+                if present.contains(&key) {
+                    continue;
+                }
+                for &mirror in mirrors {
+                    if let Some(object) = read_object(mirror, &key)? {
+                        primary.put(&key, &object)?;
+                        progress.copied(object.len() as u64);
+                        copied += 1;
+                        present.insert(key);
+                        break;
+                    }
+                }
+            }
+        }
+        progress.finish();
+        Ok(copied)
+    }
+
+    /// Stores the blobs that are still held in memory. Must be called before the database
+    /// refers to them.
+    pub fn flush(&self) -> Result<()> {
+        self.packs.lock().unwrap().flush(&*self.backend)
+    }
+
+    /// Rewrites the packs that contain blobs not in `live`, without them. Packed copies of blobs
+    /// that also have an object of their own, like repaired ones, are dropped too.
+    pub fn repack(&self, live: &HashSet<Hash>) -> Result<RepackStats> {
+        let mut live = live.clone();
+        for hash in self.list_object_blobs()? {
+            live.remove(&hash);
+        }
+        self.packs.lock().unwrap().repack(&*self.backend, &live)
+    }
+
+    /// The hashes of all stored blobs.
+    pub fn list_blobs(&self) -> Result<Vec<Hash>> {
+        let mut hashes = self.list_object_blobs()?;
+        hashes.extend(self.packs.lock().unwrap().iter().map(|entry| entry.hash));
+        Ok(hashes)
+    }
+
+    /// The keys of the pack indexes that can't be read. The blobs in their packs can't be
+    /// found, so they count as missing.
+    pub fn unreadable_indexes(&self) -> Vec<String> {
+        let packs = self.packs.lock().unwrap();
+        packs
+            .unreadable()
+            .iter()
+            .map(|&id| pack::index_key(id))
+            .collect()
+    }
+
+    /// Moves the packs whose index can't be read, and their indexes, to `quarantine/`.
+    /// Only safe once the blobs backups need from them are stored again some other way.
+    pub fn quarantine_unreadable_packs(&self) -> Result<()> {
+        let mut packs = self.packs.lock().unwrap();
+        for id in packs.unreadable().clone() {
+            for key in [pack::pack_key(id), pack::index_key(id)] {
+                if let Some(object) = read_object(&*self.backend, &key)? {
+                    self.backend.put(&quarantine_key(&key), &object)?;
+                }
+                self.backend.delete(&key)?;
+            }
+            packs.remove_unreadable(id);
+        }
+        Ok(())
+    }
+
+    /// The hashes of the blobs that have an object of their own.
+    fn list_object_blobs(&self) -> Result<Vec<Hash>> {
+        let mut hashes = Vec::new();
+        for key in self.backend.list(DATA_PREFIX)? {
+            let name = key.rsplit('/').next().unwrap_or_default();
+            let hex = name.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(name);
+            if let Ok(hash) = blake3::Hash::from_hex(hex) {
+                hashes.push(hash.into());
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Content hashes are keyed in encrypted vaults, so blob names don't reveal what is stored.
+    fn hasher(&self) -> blake3::Hasher {
+        match &self.cipher {
+            Some(cipher) => cipher.hasher(),
+            None => blake3::Hasher::new(),
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> Hash {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+
+    fn blob_reader<'a>(&self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        match &self.cipher {
+            Some(cipher) => Ok(Box::new(cipher.decrypt_reader(reader)?)),
+            None => Ok(Box::new(reader)),
+        }
+    }
+
+    /// Opens a file stored in several blobs, reading them one after another.
+    pub fn open_blobs<'a>(&'a self, blobs: &'a [Hash]) -> Box<dyn Read + 'a> {
+        Box::new(BlobsReader {
+            storage: self,
+            blobs: blobs.iter(),
+            current: None,
+        })
+    }
+
+    /// Reads a whole blob into memory.
+    pub fn read_blob(&self, hash: Hash) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open_blob(hash)?
+            .read_to_end(&mut buf)
+            .with_context(|| format!("reading blob {hash}"))?;
+        Ok(buf)
+    }
+
+    /// Reads a blob back and hashes its contents, to check them against the hash it is
+    /// stored under.
+    pub fn rehash_blob(&self, hash: Hash, progress: &mut dyn Progress) -> Result<Hash> {
+        if self.backend.replicas().is_empty() {
+            return self.rehash_blob_in(&*self.backend, hash, progress);
+        }
+        let mut blob = self.open_blob(hash)?;
+        let mut hasher = self.hasher();
+        let n = io::copy(&mut blob, &mut hasher).with_context(|| format!("reading blob {hash}"))?;
+        progress.hashed(n);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Like `rehash_blob`, but hashes each copy of a mirrored vault on its own, so that a
+    /// damaged copy is found even though an intact one would be read instead. Returns one
+    /// result per copy, or a single one if the vault has no mirrors.
+    pub fn rehash_copies(&self, hash: Hash, progress: &mut dyn Progress) -> Vec<Result<Hash>> {
+        let replicas = self.backend.replicas();
+        if replicas.is_empty() {
+            return vec![self.rehash_blob_in(&*self.backend, hash, progress)];
+        }
+        replicas
+            .iter()
+            .map(|&replica| self.rehash_blob_in(replica, hash, progress))
+            .collect()
+    }
 
-        let dir = dest.parent().unwrap();
-        if !dir.exists() {
-            fs::create_dir(dest.parent().unwrap()).context_2("mkdir", dir)?;
+    fn rehash_blob_in(
+        &self,
+        backend: &dyn Backend,
+        hash: Hash,
+        progress: &mut dyn Progress,
+    ) -> Result<Hash> {
+        // Not checked while it is read, so that what the blob holds instead can be told
+        let mut blob = self.open_blob_in(backend, hash)?;
+        let mut hasher = self.hasher();
+        let n = io::copy(&mut blob, &mut hasher).with_context(|| format!("reading blob {hash}"))?;
+        progress.hashed(n);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Copies `source` into storage, hashing it in the same pass. Large files are chunked.
+    pub fn insert_file(&self, source: &Path, progress: &mut dyn Progress) -> Result<StoredFile> {
+        let f = File::open(source).context_2("open", source)?;
+        let size = f.metadata().context_2("stat", source)?.len();
+        progress.reading(source);
+        if size >= CHUNKING_THRESHOLD {
+            self.insert_chunked(f, progress)
+        } else {
+            let hash = self.insert_reader(f, progress)?;
+            Ok(StoredFile {
+                hash,
+                chunks: Vec::new(),
+            })
         }
+    }
+
+    /// Splits `reader` into content-defined chunks and stores each of them as a blob.
+    /// If there turns out to be only one chunk, it is stored like an unchunked file.
+    pub fn insert_chunked(
+        &self,
+        reader: impl Read,
+        progress: &mut dyn Progress,
+    ) -> Result<StoredFile> {
+        let mut hasher = self.hasher();
+        let mut chunks = Vec::new();
+        let chunker = StreamCDC::new(reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        for chunk in chunker {
+            let chunk = chunk.context("reading")?;
+            hasher.update(&chunk.data);
+            chunks.push(self.insert_reader(chunk.data.as_slice(), progress)?);
+        }
+        if chunks.len() <= 1 {
+            let hash = match chunks.pop() {
+                Some(hash) => hash,
+                None => self.insert_reader(io::empty(), progress)?,
+            };
+            return Ok(StoredFile {
+                hash,
+                chunks: Vec::new(),
+            });
+        }
+        Ok(StoredFile {
+            hash: hasher.finalize().into(),
+            chunks,
+        })
+    }
+
+    /// Reads `reader` into memory while hashing it, and stores it unless a blob with the same
+    /// hash is already present. Blobs are small enough for this, since large files are chunked.
+    pub fn insert_reader(
+        &self,
+        mut reader: impl Read,
+        progress: &mut dyn Progress,
+    ) -> Result<Hash> {
+        let mut hasher = self.hasher();
+        let mut data = Vec::new();
+        let mut buf = vec![0; COPY_BUF_SIZE];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("reading"),
+            };
+            hasher.update(&buf[..n]);
+            data.extend_from_slice(&buf[..n]);
+            progress.hashed(n as u64);
+        }
+
+        let hash = Hash::from(hasher.finalize());
+        if !self.contains(hash)? {
+            self.store(hash, &data)?;
+            progress.copied(data.len() as u64);
+        }
+        Ok(hash)
+    }
+
+    /// Puts a blob into a pack if it is small, or into an object of its own.
+    fn store(&self, hash: Hash, data: &[u8]) -> Result<()> {
+        let (stored, compressed) = self.encode(data)?;
+        if stored.len() < MAX_PACKED_BLOB_SIZE {
+            let mut packs = self.packs.lock().unwrap();
+            return packs.add(&*self.backend, hash, &stored, compressed);
+        }
+        self.backend
+            .put(&Self::object_key(hash, compressed), &stored)
+    }
+
+    /// Compresses the blob if that makes it smaller, and encrypts it. Returns the bytes to store
+    /// and whether they are compressed.
+    fn encode<'a>(&self, data: &'a [u8]) -> Result<(Cow<'a, [u8]>, bool)> {
+        let compressed = match self.compression_level {
+            Some(level) => {
+                let compressed = zstd::bulk::compress(data, level).context("compressing")?;
+                (compressed.len() < data.len()).then_some(compressed)
+            }
+            None => None,
+        };
+        let is_compressed = compressed.is_some();
+        let stored = match (&self.cipher, compressed) {
+            (Some(cipher), compressed) => {
+                Cow::Owned(cipher.encrypt(compressed.as_deref().unwrap_or(data))?)
+            }
+            (None, Some(compressed)) => Cow::Owned(compressed),
+            (None, None) => Cow::Borrowed(data),
+        };
+        Ok((stored, is_compressed))
+    }
 
-        let source_disp = source.display();
-        let dest_disp = dest.display();
-        fs::copy(source, &dest).with_context(|| format!("copying {source_disp} to {dest_disp}"))?;
+    /// The key of the object a blob gets when it isn't packed, which depends on whether it is
+    /// stored compressed.
+    pub fn object_key(hash: Hash, compressed: bool) -> String {
+        match compressed {
+            true => Self::compressed_key_of(hash),
+            false => Self::key_of(hash),
+        }
+    }
+
+    /// Stores a good copy of a blob that is missing or corrupted, as an object of its own,
+    /// which is read instead of a packed copy. Any existing copies are moved to `quarantine/`.
+    pub fn replace_blob(&self, hash: Hash, data: &[u8]) -> Result<()> {
+        ensure!(
+            self.hash(data) == hash,
+            "the replacement for blob {hash} has different contents"
+        );
+        self.quarantine(hash)?;
+        let (stored, compressed) = self.encode(data)?;
+        self.backend
+            .put(&Self::object_key(hash, compressed), &stored)?;
+        // The corrupted copy may have been stored the other way
+        self.backend.delete(&Self::object_key(hash, !compressed))
+    }
+
+    /// Copies the stored bytes of every copy of a blob to `quarantine/`, as they are.
+    fn quarantine(&self, hash: Hash) -> Result<()> {
+        let mut copies = Vec::new();
+        for key in [Self::key_of(hash), Self::compressed_key_of(hash)] {
+            if let Some(object) = self.backend.get(&key)? {
+                copies.push((quarantine_key(&key), object));
+            }
+        }
+        {
+            let packs = self.packs.lock().unwrap();
+            if let Some(entry) = packs.get(hash) {
+                let object = packs.open(&*self.backend, &entry)?;
+                copies.push((format!("{QUARANTINE_PREFIX}{hash}.packed"), object));
+            }
+        }
+        for (key, mut object) in copies {
+            let mut data = Vec::new();
+            object
+                .read_to_end(&mut data)
+                .with_context(|| format!("reading blob {hash}"))?;
+            self.backend.put(&key, &data)?;
+        }
         Ok(())
     }
 
-    pub fn insert_iter(
+    /// Stores parity for the blob objects and packs that have none, like the ones stored
+    /// before parity was turned on. Returns how many objects got parity. Parity whose object
+    /// is gone is kept, since the object may still be rebuilt from it. Parity computed from
+    /// a corrupted object rebuilds it corrupted, so storage should be verified first.
+    pub fn add_parity(&self, percent: u8, progress: &mut dyn Progress) -> Result<usize> {
+        self.flush()?;
+        let mut objects = self.backend.list(DATA_PREFIX)?;
+        objects.extend(self.backend.list(PACKS_PREFIX)?);
+        objects.retain(|key| parity::is_protected(key));
+        let have_parity: HashSet<String> = self
+            .backend
+            .list(PARITY_PREFIX)?
+            .iter()
+            .filter_map(|key| parity::object_key(key))
+            .map(str::to_owned)
+            .collect();
+
+        objects.retain(|key| !have_parity.contains(key));
+        progress.expect(objects.len() as u64, 0);
+        for key in &objects {
+            let Some(data) = read_object(&*self.backend, key)? else {
+                continue;
+            };
+            self.backend
+                .put(&parity::parity_key(key), &parity::encode(&data, percent)?)?;
+            progress.hashed(data.len() as u64);
+        }
+        progress.finish();
+        Ok(objects.len())
+    }
+
+    /// Rebuilds the objects that hold a blob from their parity, if they are damaged and have
+    /// parity. The damaged objects are moved to `quarantine/`. Returns whether any object was
+    /// rebuilt; with `dry_run`, whether any could be, without writing anything.
+    pub fn repair_from_parity(&self, hash: Hash, dry_run: bool) -> Result<bool> {
+        let mut keys = vec![Self::key_of(hash), Self::compressed_key_of(hash)];
+        let entry = self.packs.lock().unwrap().get(hash);
+        keys.extend(entry.map(|entry| pack::pack_key(entry.pack)));
+
+        let mut rebuilt = false;
+        for key in keys {
+            let Some(parity) = read_object(&*self.backend, &parity::parity_key(&key))? else {
+                continue;
+            };
+            let object = read_object(&*self.backend, &key)?;
+            let recovered = parity::recover(object.as_deref().unwrap_or_default(), &parity)
+                .with_context(|| format!("rebuilding {key} from parity"))?;
+            let Recovered::Rebuilt(data) = recovered else {
+                continue;
+            };
+            rebuilt = true;
+            if dry_run {
+                continue;
+            }
+            if let Some(object) = object {
+                self.backend.put(&quarantine_key(&key), &object)?;
+            }
+            self.backend.put(&key, &data)?;
+        }
+        Ok(rebuilt)
+    }
+
+    /// Splits a file into blobs the same way `insert_file` does, without storing them,
+    /// and calls f with the hash and contents of each.
+    pub fn split_file(
         &self,
-        iter: impl IntoIterator<Item = (impl AsRef<Path>, Hash)>,
+        source: &Path,
+        mut f: impl FnMut(Hash, &[u8]) -> Result<()>,
     ) -> Result<()> {
-        for (source, hash) in iter {
-            self.insert_file(source.as_ref(), hash)
-                .context_2("inserting file into storage", source)?;
+        let mut file = File::open(source).context_2("open", source)?;
+        let size = file.metadata().context_2("stat", source)?.len();
+        if size < CHUNKING_THRESHOLD {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).context_2("reading", source)?;
+            return f(self.hash(&data), &data);
+        }
+        let chunker = StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        for chunk in chunker {
+            let chunk = chunk.context_2("reading", source)?;
+            f(self.hash(&chunk.data), &chunk.data)?;
         }
         Ok(())
     }
 
+    /// Deletes a blob that has an object of its own. Packed blobs are removed by `repack`.
     pub fn delete_file(&self, hash: Hash) -> Result<()> {
-        let path = self.path_of(hash);
-        fs::remove_file(&path).context_2("remove_file", path)
-    }
-
-    pub fn iter_files(&self) -> impl Iterator<Item = Result<PathBuf>> {
-        WalkDir::new(&self.data_dir)
-            .into_iter()
-            .skip(1)
-            .filter_map(|res| match res {
-                Ok(dir_entry) => match dir_entry.metadata().map(|m| m.is_file()) {
-                    Ok(true) => Some(Ok(dir_entry.into_path())),
-                    Ok(false) => None,
-                    Err(e) => Some(Err(e.into())),
-                },
-                Err(e) => Some(Err(e.into())),
-            })
+        ensure!(
+            self.packs.lock().unwrap().get(hash).is_none(),
+            "blob {hash} is packed, it can only be removed by repacking"
+        );
+        self.backend.delete(&Self::key_of(hash))?;
+        self.backend.delete(&Self::compressed_key_of(hash))
+    }
+}
+
+/// Reads a whole object, as it is stored.
+fn read_object(backend: &dyn Backend, key: &str) -> Result<Option<Vec<u8>>> {
+    let Some(mut object) = backend.get(key)? else {
+        return Ok(None);
+    };
+    let mut data = Vec::new();
+    object
+        .read_to_end(&mut data)
+        .with_context(|| format!("reading {key}"))?;
+    Ok(Some(data))
+}
+
+/// Where a damaged copy of an object is kept.
+fn quarantine_key(key: &str) -> String {
+    let name = key.rsplit('/').next().unwrap_or_default();
+    format!("{QUARANTINE_PREFIX}{name}")
+}
+
+/// Hashes a blob as it is read, and fails at the end if it doesn't match.
+struct VerifyReader {
+    inner: Box<dyn Read>,
+    hasher: blake3::Hasher,
+    hash: Hash,
+}
+
+impl Read for VerifyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && Hash::from(self.hasher.finalize()) != self.hash {
+            let msg = format!("blob {} is corrupted", self.hash);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(n)
+    }
+}
+
+/// Reads blobs one after another, opening each one when the previous one is done.
+struct BlobsReader<'a> {
+    storage: &'a Storage,
+    blobs: std::slice::Iter<'a, Hash>,
+    current: Option<Box<dyn Read>>,
+}
+
+impl Read for BlobsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                match current.read(buf)? {
+                    0 if !buf.is_empty() => self.current = None,
+                    n => return Ok(n),
+                }
+            }
+            let Some(&hash) = self.blobs.next() else {
+                return Ok(0);
+            };
+            let blob = self.storage.open_blob(hash).map_err(io::Error::other)?;
+            self.current = Some(blob);
+        }
     }
 }
@@ -0,0 +1,667 @@
+mod common;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use common::{pseudo_random, RecordingProgress};
+use sharedfileholder::{
+    backup_into,
+    progress::{NoProgress, Progress},
+    restore_from,
+    vault::{
+        backend::{Backend, MemoryBackend},
+        backup::{Backup, BackupFile, FileId, SkipReason, SpecialFile, XattrValue},
+        config::Config,
+        storage::StoredFile,
+        Vault,
+    },
+    Hash, MTime, ScanOptions,
+};
+
+#[test]
+fn files_on_different_devices_with_the_same_inode() {
+    let mtime = MTime::from(SystemTime::now());
+    let stored = |contents: &[u8]| StoredFile {
+        hash: Hash::from(blake3::hash(contents)),
+        chunks: Vec::new(),
+    };
+    let one = FileId { dev: 1, ino: 2 };
+    let two = FileId { dev: 2, ino: 2 };
+    let mut backup = Backup::new();
+    backup.insert_file(BackupFile::new("one/x".into(), one, mtime, 1, stored(b"x")));
+    backup.insert_file(BackupFile::new("two/y".into(), two, mtime, 1, stored(b"y")));
+    assert_eq!(backup.iter_files().len(), 2);
+    let by_id = backup.files_by_id();
+    assert_eq!(by_id[&one].path, PathBuf::from("one/x"));
+    assert_eq!(by_id[&two].path, PathBuf::from("two/y"));
+
+    // Backups from before the device was recorded still load
+    let mut json = serde_json::to_value(&backup).unwrap();
+    json["files"][0].as_object_mut().unwrap().remove("dev");
+    let old: Backup = serde_json::from_value(json).unwrap();
+    assert!(old.files_by_id().contains_key(&FileId { dev: 0, ino: 2 }));
+}
+
+#[test]
+fn skipped_directories_are_not_restored() {
+    // Like a directory that was found, but whose contents couldn't be read
+    let mut backup = Backup::new();
+    backup.insert_directory("d".into());
+    backup.insert_directory("d/sub".into());
+    backup.insert_skipped(
+        "d/sub".into(),
+        SkipReason::Error("permission denied".into()),
+    );
+    let dirs: Vec<_> = backup.iter_directories().collect();
+    assert_eq!(dirs, [Path::new("d")]);
+    assert_eq!(backup.iter_skipped().len(), 1);
+}
+
+/// Refuses to store blobs that get an object of their own, so that large files fail to be
+/// backed up while small ones go into packs.
+#[derive(Debug)]
+struct NoLooseBlobs(MemoryBackend);
+
+impl Backend for NoLooseBlobs {
+    fn put(&self, key: &str, data: &[u8]) -> eyre::Result<()> {
+        eyre::ensure!(!key.starts_with("data/"), "no space left for {key}");
+        self.0.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> eyre::Result<Option<Box<dyn Read + Send>>> {
+        self.0.get(key)
+    }
+
+    fn exists(&self, key: &str) -> eyre::Result<bool> {
+        self.0.exists(key)
+    }
+
+    fn delete(&self, key: &str) -> eyre::Result<()> {
+        self.0.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> eyre::Result<Vec<String>> {
+        self.0.list(prefix)
+    }
+}
+
+#[test]
+fn hardlinks_round_trip() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::create_dir(src.join("d")).unwrap();
+    fs::write(src.join("a"), "contents").unwrap();
+    fs::hard_link(src.join("a"), src.join("d/b")).unwrap();
+
+    let vault = backup_dir(&src, &ScanOptions::default());
+    let bkup = vault.database.get_backup("b").unwrap();
+    // One path is stored as a file, and the other as a link to it
+    let links: Vec<_> = bkup.iter_hardlinks().collect();
+    assert_eq!(links.len(), 1);
+    let (link, original) = links[0];
+    let mut paths = [link.clone(), original.clone()];
+    paths.sort();
+    assert_eq!(paths, [PathBuf::from("a"), PathBuf::from("d/b")]);
+    assert_eq!(bkup.iter_files().len(), 1);
+    assert_eq!(bkup.find_file(link).unwrap().path, *original);
+
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    let a = fs::metadata(dest.join("a")).unwrap();
+    let b = fs::metadata(dest.join("d/b")).unwrap();
+    assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+    assert_eq!(a.nlink(), 2);
+    assert_eq!(fs::read(dest.join("d/b")).unwrap(), b"contents");
+}
+
+#[test]
+fn hardlinks_to_a_skipped_file_are_skipped() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    let large = pseudo_random(1 << 20);
+    fs::write(src.join("a"), &large).unwrap();
+    fs::hard_link(src.join("a"), src.join("b")).unwrap();
+    fs::write(src.join("c"), "small").unwrap();
+
+    let backend = NoLooseBlobs(MemoryBackend::new());
+    let mut vault = Vault::create(Box::new(backend), Config::default(), None).unwrap();
+    let sources = [(&*src, PathBuf::new())];
+    let opts = ScanOptions {
+        continue_on_error: true,
+        ..Default::default()
+    };
+    let summary = backup_into(&mut vault, "b", &sources, &opts, &mut NoProgress).unwrap();
+    assert_eq!(summary.n_errors, 2);
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert_eq!(bkup.iter_hardlinks().len(), 0);
+    let skipped: Vec<_> = bkup.iter_skipped().map(|(path, _)| path.clone()).collect();
+    assert_eq!(skipped, [PathBuf::from("a"), PathBuf::from("b")]);
+
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    assert_eq!(fs::read(dest.join("c")).unwrap(), b"small");
+}
+
+#[test]
+fn dereferenced_symlinks_are_not_hardlinks() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::create_dir(src.join("d")).unwrap();
+    fs::write(src.join("d/a"), "contents").unwrap();
+    std::os::unix::fs::symlink("d/a", src.join("link")).unwrap();
+
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let sources = [(&*src, PathBuf::new())];
+    let opts = ScanOptions {
+        dereference: true,
+        ..Default::default()
+    };
+    backup_into(&mut vault, "b", &sources, &opts, &mut NoProgress).unwrap();
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert_eq!(bkup.iter_hardlinks().len(), 0);
+    assert_eq!(bkup.iter_files().len(), 2);
+
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    for path in ["d/a", "link"] {
+        let metadata = fs::symlink_metadata(dest.join(path)).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.nlink(), 1);
+        assert_eq!(fs::read(dest.join(path)).unwrap(), b"contents");
+    }
+}
+
+/// Backs up src into a new in-memory vault, as the backup "b".
+fn backup_dir(src: &Path, opts: &ScanOptions) -> Vault {
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let sources = [(src, PathBuf::new())];
+    backup_into(&mut vault, "b", &sources, opts, &mut NoProgress).unwrap();
+    vault
+}
+
+fn skipped(vault: &Vault) -> Vec<(PathBuf, SkipReason)> {
+    let bkup = vault.database.get_backup("b").unwrap();
+    bkup.iter_skipped()
+        .map(|(path, reason)| (path.clone(), reason.clone()))
+        .collect()
+}
+
+#[test]
+fn cache_dirs_are_skipped() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    let tags: [(&str, &[u8]); 3] = [
+        (
+            "cache",
+            b"Signature: 8a477f597d28d172789f06886806bc55\n# a comment",
+        ),
+        ("wrong", b"Signature: 0123456789abcdef0123456789abcdef\n"),
+        ("short", b"Signature: 8a477f59"),
+    ];
+    for (dir, tag) in tags {
+        fs::create_dir(src.join(dir)).unwrap();
+        fs::write(src.join(dir).join("CACHEDIR.TAG"), tag).unwrap();
+        fs::write(src.join(dir).join("file"), "contents").unwrap();
+    }
+
+    let vault = backup_dir(&src, &ScanOptions::default());
+    assert_eq!(
+        skipped(&vault),
+        [(PathBuf::from("cache"), SkipReason::CacheDir)]
+    );
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert!(bkup.find_file(Path::new("cache/file")).is_none());
+    assert!(bkup.find_file(Path::new("wrong/file")).is_some());
+    assert!(bkup.find_file(Path::new("short/file")).is_some());
+
+    let opts = ScanOptions {
+        include_caches: true,
+        ..Default::default()
+    };
+    let vault = backup_dir(&src, &opts);
+    assert!(skipped(&vault).is_empty());
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert!(bkup.find_file(Path::new("cache/file")).is_some());
+}
+
+#[test]
+fn pseudo_filesystems_are_skipped() {
+    if !Path::new("/proc/self").exists() {
+        eprintln!("skipping, /proc is not mounted");
+        return;
+    }
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("file"), "contents").unwrap();
+    std::os::unix::fs::symlink("/proc", src.join("proc")).unwrap();
+
+    let opts = ScanOptions {
+        dereference: true,
+        ..Default::default()
+    };
+    let vault = backup_dir(&src, &opts);
+    assert_eq!(
+        skipped(&vault),
+        [(PathBuf::from("proc"), SkipReason::PseudoFs)]
+    );
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert_eq!(bkup.iter_files().len(), 1);
+}
+
+#[test]
+fn files_filtered_by_size_and_age() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("large"), [0; 2000]).unwrap();
+    fs::write(src.join("small"), [0; 10]).unwrap();
+    fs::write(src.join("old"), [0; 500]).unwrap();
+    fs::write(src.join("kept"), [0; 500]).unwrap();
+    let old_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(946684800);
+    let old = fs::File::options()
+        .write(true)
+        .open(src.join("old"))
+        .unwrap();
+    old.set_modified(old_mtime).unwrap();
+
+    let opts = ScanOptions {
+        max_file_size: Some(1000),
+        min_file_size: Some(100),
+        newer_than: Some(MTime::from(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1577836800),
+        )),
+        ..Default::default()
+    };
+    let vault = backup_dir(&src, &opts);
+    let expected = [
+        (PathBuf::from("large"), SkipReason::TooLarge { size: 2000 }),
+        (
+            PathBuf::from("old"),
+            SkipReason::TooOld {
+                mtime: MTime::from(old_mtime),
+            },
+        ),
+        (PathBuf::from("small"), SkipReason::TooSmall { size: 10 }),
+    ];
+    assert_eq!(skipped(&vault), expected);
+    let bkup = vault.database.get_backup("b").unwrap();
+    let files: Vec<_> = bkup.iter_files().map(|file| file.path.clone()).collect();
+    assert_eq!(files, [PathBuf::from("kept")]);
+}
+
+/// Sets a user xattr on path, or returns false if the filesystem doesn't support them.
+fn set_user_xattr(path: &Path, name: &str, value: &[u8]) -> bool {
+    match xattr::set(path, name, value) {
+        Ok(()) => true,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => false,
+        Err(e) => panic!("setting {name}: {e}"),
+    }
+}
+
+#[test]
+fn xattrs_round_trip() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("file"), "contents").unwrap();
+    fs::create_dir(src.join("dir")).unwrap();
+    // Large values are kept in storage instead of the backup
+    let large = vec![b'x'; 1000];
+    if !set_user_xattr(&src.join("file"), "user.small", b"value") {
+        eprintln!("skipping, the filesystem doesn't support xattrs");
+        return;
+    }
+    assert!(set_user_xattr(&src.join("file"), "user.large", &large));
+    assert!(set_user_xattr(&src.join("dir"), "user.dir", b""));
+
+    let vault = backup_dir(&src, &ScanOptions::default());
+    let bkup = vault.database.get_backup("b").unwrap();
+    let (_, xattrs) = bkup
+        .iter_xattrs()
+        .find(|(path, _)| *path == Path::new("file"))
+        .unwrap();
+    assert!(matches!(xattrs["user.large"], XattrValue::Stored(_)));
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    let get = |path: &str, name: &str| xattr::get(dest.join(path), name).unwrap();
+    assert_eq!(get("file", "user.small").unwrap(), b"value");
+    assert_eq!(get("file", "user.large").unwrap(), large);
+    assert_eq!(get("dir", "user.dir").unwrap(), b"");
+}
+
+#[test]
+fn skipped_xattr_namespaces() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("file"), "contents").unwrap();
+    if !set_user_xattr(&src.join("file"), "user.name", b"value") {
+        eprintln!("skipping, the filesystem doesn't support xattrs");
+        return;
+    }
+
+    let vault = backup_dir(&src, &ScanOptions::default());
+    let dest = mktemp::Temp::new_dir().unwrap();
+    let skip = ["user".to_owned()];
+    restore_from(&vault, "b", &dest, &skip, &mut NoProgress).unwrap();
+    assert_eq!(fs::read(dest.join("file")).unwrap(), b"contents");
+    assert!(xattr::get(dest.join("file"), "user.name")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn fifo_round_trip() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    let fifo = std::ffi::CString::new(src.join("fifo").as_os_str().as_bytes()).unwrap();
+    // SAFETY: fifo is a valid nul-terminated string
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    fs::write(src.join("file"), "contents").unwrap();
+
+    let vault = backup_dir(&src, &ScanOptions::default());
+    let bkup = vault.database.get_backup("b").unwrap();
+    let specials: Vec<_> = bkup.iter_specials().collect();
+    assert_eq!(specials, [(&PathBuf::from("fifo"), &SpecialFile::Fifo)]);
+
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    let file_type = fs::symlink_metadata(dest.join("fifo")).unwrap().file_type();
+    assert!(file_type.is_fifo());
+    assert_eq!(fs::read(dest.join("file")).unwrap(), b"contents");
+}
+
+#[test]
+fn unreadable_dirs_are_skipped_with_their_contents() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    // A CACHEDIR.TAG that is a directory can't be read, even by root
+    fs::create_dir_all(src.join("dir/CACHEDIR.TAG")).unwrap();
+    fs::write(src.join("dir/file"), "contents").unwrap();
+    fs::write(src.join("file"), "contents").unwrap();
+
+    let opts = ScanOptions {
+        continue_on_error: true,
+        ..Default::default()
+    };
+    let vault = backup_dir(&src, &opts);
+    let skipped = skipped(&vault);
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, PathBuf::from("dir"));
+    assert!(matches!(skipped[0].1, SkipReason::Error(_)));
+    let bkup = vault.database.get_backup("b").unwrap();
+    let files: Vec<_> = bkup.iter_files().map(|file| file.path.clone()).collect();
+    assert_eq!(files, [PathBuf::from("file")]);
+    assert!(!bkup.iter_directories().any(|dir| dir.starts_with("dir")));
+}
+
+#[test]
+fn root_symlinks_are_followed_by_default() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::create_dir(src.join("real")).unwrap();
+    fs::write(src.join("real/file"), "contents").unwrap();
+    std::os::unix::fs::symlink("real", src.join("link")).unwrap();
+    let link = src.join("link");
+
+    let vault = backup_dir(&link, &ScanOptions::default());
+    let bkup = vault.database.get_backup("b").unwrap();
+    assert!(bkup.find_file(Path::new("file")).is_some());
+
+    let opts = ScanOptions {
+        store_root_symlink: true,
+        ..Default::default()
+    };
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let sources = [(&*link, PathBuf::from("link"))];
+    backup_into(&mut vault, "b", &sources, &opts, &mut NoProgress).unwrap();
+    let bkup = vault.database.get_backup("b").unwrap();
+    let symlinks: Vec<_> = bkup.iter_symlinks().collect();
+    assert_eq!(symlinks, [(&PathBuf::from("link"), &PathBuf::from("real"))]);
+    assert_eq!(bkup.iter_files().len(), 0);
+}
+
+/// Appends to a file each time it is about to be read, up to `times` times.
+struct AppendWhileReading {
+    path: PathBuf,
+    times: usize,
+}
+
+impl Progress for AppendWhileReading {
+    fn reading(&mut self, path: &Path) {
+        if path == self.path && self.times > 0 {
+            self.times -= 1;
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(b" and more").unwrap();
+        }
+    }
+}
+
+#[test]
+fn files_changed_during_backup() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    let path = src.join("file");
+    fs::write(&path, "contents").unwrap();
+    // So that appending always changes the mtime
+    let scanned_mtime = SystemTime::now() - Duration::from_secs(3600);
+    let set_old_mtime = || {
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(scanned_mtime).unwrap();
+    };
+    set_old_mtime();
+    let sources = [(&*src, PathBuf::new())];
+    let opts = ScanOptions::default();
+
+    // A file that changes once is copied again
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let mut progress = AppendWhileReading {
+        path: path.clone(),
+        times: 1,
+    };
+    let summary = backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert!(summary.changed_during_backup.is_empty());
+    let file = vault
+        .database
+        .get_backup("b")
+        .unwrap()
+        .find_file(Path::new("file"))
+        .unwrap();
+    let contents = fs::read(&path).unwrap();
+    assert_eq!(file.hash, Hash::from(blake3::hash(&contents)));
+    assert_eq!(file.size, contents.len() as u64);
+
+    // A file that keeps changing is reported, and keeps the mtime it was scanned with,
+    // so that the next backup copies it again
+    fs::write(&path, "contents").unwrap();
+    set_old_mtime();
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let mut progress = AppendWhileReading {
+        path: path.clone(),
+        times: usize::MAX,
+    };
+    let summary = backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert_eq!(summary.changed_during_backup, [path.as_path()]);
+    let file = vault
+        .database
+        .get_backup("b")
+        .unwrap()
+        .find_file(Path::new("file"))
+        .unwrap();
+    assert_eq!(file.mtime, MTime::from(scanned_mtime));
+
+    let mut progress = AppendWhileReading {
+        path: path.clone(),
+        times: 0,
+    };
+    let summary = backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert_eq!(summary.n_new_files, 1);
+    assert!(summary.changed_during_backup.is_empty());
+}
+
+#[test]
+fn progress_of_backup_and_restore() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("one"), "first file").unwrap();
+    fs::write(src.join("two"), "second").unwrap();
+    let total = ("first file".len() + "second".len()) as u64;
+    let sources = [(&*src, PathBuf::new())];
+    let opts = ScanOptions::default();
+
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let mut progress = RecordingProgress::default();
+    backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert_eq!(progress.expected, None);
+    progress.scanned.sort();
+    assert_eq!(
+        progress.scanned,
+        [(src.join("one"), 10, false), (src.join("two"), 6, false)]
+    );
+    assert_eq!(progress.read.len(), 2);
+    assert_eq!(progress.hashed, total);
+    assert_eq!(progress.copied, total);
+    assert!(progress.finished);
+
+    // The next backup expects the totals of this one, and only reads what changed
+    let one = fs::File::options()
+        .write(true)
+        .open(src.join("one"))
+        .unwrap();
+    one.set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    let mut progress = RecordingProgress::default();
+    backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert_eq!(progress.expected, Some((2, total)));
+    progress.scanned.sort();
+    assert_eq!(
+        progress.scanned,
+        [(src.join("one"), 10, false), (src.join("two"), 6, true)]
+    );
+    assert_eq!(progress.read, [src.join("one")]);
+    assert_eq!(progress.hashed, 10);
+    assert_eq!(progress.copied, 0);
+
+    let dest = mktemp::Temp::new_dir().unwrap();
+    let mut progress = RecordingProgress::default();
+    restore_from(&vault, "b", &dest, &[], &mut progress).unwrap();
+    assert_eq!(progress.expected, Some((2, total)));
+    progress.read.sort();
+    assert_eq!(progress.read, [PathBuf::from("one"), PathBuf::from("two")]);
+    assert_eq!(progress.copied, total);
+    assert!(progress.finished);
+}
+
+#[test]
+fn several_sources_side_by_side() {
+    let one = mktemp::Temp::new_dir().unwrap();
+    let two = mktemp::Temp::new_dir().unwrap();
+    fs::write(one.join("file"), "one").unwrap();
+    fs::create_dir(two.join("d")).unwrap();
+    fs::write(two.join("d/file"), "two").unwrap();
+
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let opts = ScanOptions::default();
+    let sources = [(&*one, PathBuf::from("one")), (&*two, PathBuf::from("two"))];
+    backup_into(&mut vault, "b", &sources, &opts, &mut NoProgress).unwrap();
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, "b", &dest, &[], &mut NoProgress).unwrap();
+    assert_eq!(fs::read(dest.join("one/file")).unwrap(), b"one");
+    assert_eq!(fs::read(dest.join("two/d/file")).unwrap(), b"two");
+    let mut top: Vec<_> = fs::read_dir(&*dest)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    top.sort();
+    assert_eq!(top, ["one", "two"]);
+
+    // Both would end up in the same directory
+    let sources = [
+        (&*one, PathBuf::from("same")),
+        (&*two, PathBuf::from("same")),
+    ];
+    let e = backup_into(&mut vault, "c", &sources, &opts, &mut NoProgress).unwrap_err();
+    assert_eq!(e.to_string(), "same is used as a name more than once");
+    assert!(vault.database.get_backup("c").is_none());
+}
+
+#[test]
+fn several_sources_from_the_command_line() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let one = mktemp::Temp::new_dir().unwrap();
+    let two = mktemp::Temp::new_dir().unwrap();
+    let mount_point = mktemp::Temp::new_dir().unwrap();
+    fs::write(one.join("file"), "one").unwrap();
+    fs::write(two.join("file"), "two").unwrap();
+    let run = |args: &[&str]| {
+        let args = [&["-v", vault.to_str().unwrap()], args].concat();
+        sharedfileholder::main_with_args(&args)
+    };
+    run(&["init"]).unwrap();
+
+    let named = format!("named={}", two.to_str().unwrap());
+    let same = format!(
+        "{}={}",
+        one.file_name().unwrap().to_str().unwrap(),
+        two.to_str().unwrap()
+    );
+    assert!(run(&["backup", "b", one.to_str().unwrap(), &same]).is_err());
+    run(&["backup", "b", one.to_str().unwrap(), &named]).unwrap();
+
+    // The first is named after its directory
+    run(&["mount", "b", mount_point.to_str().unwrap()]).unwrap();
+    let derived = mount_point.join(one.file_name().unwrap());
+    assert_eq!(fs::read(derived.join("file")).unwrap(), b"one");
+    assert_eq!(fs::read(mount_point.join("named/file")).unwrap(), b"two");
+}
+
+#[test]
+fn symlink_loops_are_skipped() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::create_dir_all(src.join("a/d")).unwrap();
+    fs::write(src.join("a/file"), "contents").unwrap();
+    std::os::unix::fs::symlink("..", src.join("a/d/up")).unwrap();
+
+    let mut vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let sources = [(&*src, PathBuf::from("s"))];
+    let opts = ScanOptions {
+        dereference: true,
+        ..Default::default()
+    };
+    let summary = backup_into(&mut vault, "b", &sources, &opts, &mut NoProgress).unwrap();
+    assert_eq!(summary.n_symlink_loops, 1);
+    assert_eq!(summary.n_files, 1);
+    assert_eq!(
+        skipped(&vault),
+        [(
+            PathBuf::from("s/a/d/up"),
+            SkipReason::SymlinkLoop {
+                target: PathBuf::from("s/a")
+            }
+        )]
+    );
+}
+
+#[test]
+fn backups_without_devices_are_trusted() {
+    let src = mktemp::Temp::new_dir().unwrap();
+    fs::write(src.join("file"), "contents").unwrap();
+    let sources = [(&*src, PathBuf::new())];
+    let opts = ScanOptions::default();
+    let mut vault = backup_dir(&src, &opts);
+
+    // As if the backup was made before devices were recorded
+    let bkup = vault.database.get_backup("b").unwrap();
+    let mut json = serde_json::to_value(bkup).unwrap();
+    json["files"][0].as_object_mut().unwrap().remove("dev");
+    let old: Backup = serde_json::from_value(json).unwrap();
+    vault.database.insert_backup("b", old);
+
+    let mut progress = RecordingProgress::default();
+    backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert!(progress.read.is_empty());
+    assert_eq!(progress.scanned.len(), 1);
+    assert!(progress.scanned[0].2);
+
+    // Another file that happens to have the same inode on another device is read
+    let mut json = serde_json::to_value(vault.database.get_backup("b").unwrap()).unwrap();
+    json["files"][0].as_object_mut().unwrap().remove("dev");
+    json["files"][0]["size"] = 1.into();
+    let old: Backup = serde_json::from_value(json).unwrap();
+    vault.database.insert_backup("b", old);
+    let mut progress = RecordingProgress::default();
+    backup_into(&mut vault, "b", &sources, &opts, &mut progress).unwrap();
+    assert_eq!(progress.read, [src.join("file")]);
+}
@@ -0,0 +1,51 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use sharedfileholder::vault::Vault;
+
+#[test]
+fn backup_stdin_and_restore() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let dest = mktemp::Temp::new_dir().unwrap();
+    let vault_dir = vault.to_str().unwrap();
+    sharedfileholder::main_with_args(&["init", "-v", vault_dir]).unwrap();
+
+    // Large enough to be split into several chunks
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..5 << 20)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_backup"))
+        .args([
+            "-v",
+            vault_dir,
+            "backup",
+            "b",
+            "--stdin",
+            "--stdin-filename",
+        ])
+        .arg("dump.sql")
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&data).unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let opened = Vault::open(Some(vault.to_path_buf()), None).unwrap();
+    let bkup = opened.database.get_backup("b").unwrap();
+    let file = bkup.find_file(Path::new("dump.sql")).unwrap();
+    assert_eq!(file.size, data.len() as u64);
+    assert!(file.chunks.len() > 1);
+    drop(opened);
+
+    let dest_dir = dest.to_str().unwrap();
+    sharedfileholder::main_with_args(&["restore", "-v", vault_dir, "b", dest_dir]).unwrap();
+    assert!(fs::read(dest.join("dump.sql")).unwrap() == data);
+}
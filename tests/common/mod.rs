@@ -0,0 +1,89 @@
+//! Fixtures shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+use sharedfileholder::{
+    backup_into,
+    progress::{NoProgress, Progress},
+    vault::Vault,
+    Hash, ScanOptions, Summary,
+};
+
+/// The name of the backup `backup_src` makes.
+pub const BACKUP: &str = "b";
+
+/// Backs up this crate's src directory as src/ in `BACKUP`.
+pub fn backup_src(vault: &mut Vault) -> Summary {
+    let sources = [(Path::new("./src"), "src".into())];
+    backup_into(
+        vault,
+        BACKUP,
+        &sources,
+        &ScanOptions::default(),
+        &mut NoProgress,
+    )
+    .unwrap()
+}
+
+/// The hash of src/lib.rs in the backup made by `backup_src`.
+pub fn lib_rs(vault: &Vault) -> Hash {
+    vault
+        .database
+        .get_backup(BACKUP)
+        .unwrap()
+        .find_file(Path::new("src/lib.rs"))
+        .unwrap()
+        .hash
+}
+
+/// Deterministic data that doesn't compress or repeat.
+pub fn pseudo_random(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Remembers every progress update.
+#[derive(Debug, Default)]
+pub struct RecordingProgress {
+    pub expected: Option<(u64, u64)>,
+    /// Each scanned file, with its size and whether it was unchanged
+    pub scanned: Vec<(PathBuf, u64, bool)>,
+    pub read: Vec<PathBuf>,
+    pub hashed: u64,
+    pub copied: u64,
+    pub finished: bool,
+}
+
+impl Progress for RecordingProgress {
+    fn expect(&mut self, files: u64, bytes: u64) {
+        self.expected = Some((files, bytes));
+    }
+
+    fn scanned(&mut self, path: &Path, bytes: u64, unchanged: bool) {
+        self.scanned.push((path.to_path_buf(), bytes, unchanged));
+    }
+
+    fn reading(&mut self, path: &Path) {
+        self.read.push(path.to_path_buf());
+    }
+
+    fn hashed(&mut self, bytes: u64) {
+        self.hashed += bytes;
+    }
+
+    fn copied(&mut self, bytes: u64) {
+        self.copied += bytes;
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use std::{fs, io::Read};
+
+use common::{backup_src, lib_rs, BACKUP};
+use sharedfileholder::{
+    progress::NoProgress,
+    restore_from,
+    vault::{
+        backend::{Backend, MemoryBackend},
+        config::Config,
+        crypto::{Cipher, KeySource},
+        storage::Storage,
+        Vault,
+    },
+};
+
+#[test]
+fn backup_and_restore_in_memory() {
+    let backend = MemoryBackend::new();
+    let mut vault = Vault::in_memory(backend.clone()).unwrap();
+
+    let summary = backup_src(&mut vault);
+    assert!(summary.n_files > 0);
+    assert_eq!(summary.n_new_files, summary.n_files);
+
+    // Blobs and the database are all in the backend
+    let lib = lib_rs(&vault);
+    assert!(vault.storage.contains(lib).unwrap());
+    let contents = vault.storage.read_blob(lib).unwrap();
+    assert_eq!(contents, fs::read("src/lib.rs").unwrap());
+    assert!(backend.keys().iter().any(|key| key == "database.json"));
+
+    // Backing up again reuses the stored files
+    let summary = backup_src(&mut vault);
+    assert_eq!(summary.n_new_files, 0);
+    drop(vault);
+
+    let vault = Vault::open_backend(Box::new(backend), None).unwrap();
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, BACKUP, &dest, &[], &mut NoProgress).unwrap();
+    assert_eq!(
+        fs::read(dest.join("src/lib.rs")).unwrap(),
+        fs::read("src/lib.rs").unwrap()
+    );
+}
+
+#[test]
+fn swapped_blobs_are_detected() {
+    let backend = MemoryBackend::new();
+    let cipher = Cipher::generate();
+    let config = Config {
+        encryption: Some(cipher.wrap(KeySource::Passphrase, b"secret").unwrap()),
+        ..Default::default()
+    };
+    let vault = Vault::create(Box::new(backend.clone()), config, Some(cipher)).unwrap();
+    // Large enough that each gets an object of its own
+    let a = vault
+        .storage
+        .insert_reader(&[b'a'; 1 << 18][..], &mut NoProgress)
+        .unwrap();
+    let b = vault
+        .storage
+        .insert_reader(&[b'b'; 1 << 18][..], &mut NoProgress)
+        .unwrap();
+    let object_b = backend.object(&Storage::object_key(b, false)).unwrap();
+    backend.set_object(&Storage::object_key(a, false), &object_b);
+    assert!(vault.storage.read_blob(a).is_err());
+    assert_eq!(vault.storage.read_blob(b).unwrap(), [b'b'; 1 << 18]);
+}
+
+#[test]
+fn backend_objects() {
+    let backend = MemoryBackend::new();
+    backend.put("data/ab/one", b"0123456789").unwrap();
+    backend.put("data/cd/two", b"two").unwrap();
+    backend.put("packs/00000000.pack", b"pack").unwrap();
+
+    let read = |mut reader: Box<dyn Read + Send>| {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        contents
+    };
+    assert_eq!(
+        read(backend.get("data/ab/one").unwrap().unwrap()),
+        "0123456789"
+    );
+    assert!(backend.get("data/ab/missing").unwrap().is_none());
+    // MemoryBackend relies on the default get_range
+    assert_eq!(
+        read(backend.get_range("data/ab/one", 2, 5).unwrap()),
+        "23456"
+    );
+    assert_eq!(read(backend.get_range("data/ab/one", 8, 5).unwrap()), "89");
+    assert!(backend.get_range("data/ab/missing", 0, 1).is_err());
+
+    let mut keys = backend.list("data/").unwrap();
+    keys.sort();
+    assert_eq!(keys, ["data/ab/one", "data/cd/two"]);
+    assert!(backend.exists("data/cd/two").unwrap());
+    backend.delete("data/cd/two").unwrap();
+    assert!(!backend.exists("data/cd/two").unwrap());
+    // Deleting a missing object is fine
+    backend.delete("data/cd/two").unwrap();
+    assert_eq!(backend.list("data/").unwrap(), ["data/ab/one"]);
+}
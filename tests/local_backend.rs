@@ -0,0 +1,66 @@
+use std::{fs, io::Read, os::unix::fs::PermissionsExt};
+
+use sharedfileholder::vault::{
+    backend::{Backend, LocalBackend},
+    Vault,
+};
+
+#[test]
+fn objects_are_read_only() {
+    let tmp = mktemp::Temp::new_dir().unwrap();
+    let backend = LocalBackend::new(&tmp);
+    backend.put("data/ab/blob", b"first").unwrap();
+    let mode = fs::metadata(tmp.join("data/ab/blob"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o444);
+
+    // Objects can still be replaced and deleted
+    backend.put("data/ab/blob", b"second").unwrap();
+    let mut contents = String::new();
+    backend
+        .get("data/ab/blob")
+        .unwrap()
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "second");
+    backend.delete("data/ab/blob").unwrap();
+    assert!(!backend.exists("data/ab/blob").unwrap());
+}
+
+#[test]
+fn put_leaves_no_temporary_files() {
+    let tmp = mktemp::Temp::new_dir().unwrap();
+    let backend = LocalBackend::new(&tmp);
+    backend.put("data/ab/blob", b"contents").unwrap();
+    backend.put("packs/00000000.pack", b"pack").unwrap();
+    let mut names: Vec<_> = fs::read_dir(tmp.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["ab"]);
+}
+
+#[test]
+fn stale_temporary_files_are_ignored_and_removed() {
+    let tmp = mktemp::Temp::new_dir().unwrap();
+    let vault_dir = tmp.to_str().unwrap();
+    sharedfileholder::main_with_args(&["init", "-v", vault_dir]).unwrap();
+    // As left behind by a crash in the middle of a put
+    let stale = tmp.join("data/tmp-0");
+    fs::write(&stale, "trunc").unwrap();
+
+    let backend = LocalBackend::new(&tmp);
+    assert!(!backend
+        .list("data/")
+        .unwrap()
+        .iter()
+        .any(|key| key.contains("tmp-")));
+
+    drop(Vault::open(Some(tmp.to_path_buf()), None).unwrap());
+    assert!(!stale.exists());
+    assert_eq!(backend.remove_stale_tmp_files().unwrap(), 0);
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use std::{collections::HashSet, fs, path::Path};
+
+use common::{backup_src, lib_rs, BACKUP};
+use sharedfileholder::{
+    progress::NoProgress,
+    vault::{
+        backend::{resync, Backend, LocalBackend, MemoryBackend, MirrorBackend},
+        backup::Backup,
+        config::Config,
+        Vault,
+    },
+    Hash,
+};
+
+const PACK: &str = "packs/00000000.pack";
+
+fn open_mirrored(first: &MemoryBackend, second: &MemoryBackend) -> Vault {
+    let mirror = MirrorBackend::new(vec![Box::new(first.clone()), Box::new(second.clone())]);
+    Vault::open_backend(Box::new(mirror), None).unwrap()
+}
+
+fn live_blobs(vault: &Vault) -> HashSet<Hash> {
+    vault
+        .database
+        .iter_backups()
+        .flat_map(|(_, bkup)| bkup.iter_blobs())
+        .collect()
+}
+
+#[test]
+fn mirrored_reads_and_resync() {
+    let (first, second) = (MemoryBackend::new(), MemoryBackend::new());
+    let mirror = MirrorBackend::new(vec![Box::new(first.clone()), Box::new(second.clone())]);
+    let mut vault = Vault::create(Box::new(mirror), Config::default(), None).unwrap();
+    backup_src(&mut vault);
+    assert_eq!(first.keys(), second.keys());
+
+    // A damaged copy is skipped
+    let mut pack = first.object(PACK).unwrap();
+    pack.iter_mut().for_each(|byte| *byte ^= 0xff);
+    first.set_object(PACK, &pack);
+    let contents = vault.storage.read_blob(lib_rs(&vault)).unwrap();
+    assert_eq!(contents, fs::read("src/lib.rs").unwrap());
+
+    let (replaced, lost) = vault.storage.verify_replicas(&mut NoProgress).unwrap();
+    assert_eq!(replaced, 1);
+    assert!(lost.is_empty());
+    assert_eq!(first.object(PACK), second.object(PACK));
+
+    // An object the vault lost is copied back before the mirrors are resynced from it
+    first.delete(PACK).unwrap();
+    let live = live_blobs(&vault);
+    let restored = vault
+        .storage
+        .restore_primary(&live, &mut NoProgress)
+        .unwrap();
+    assert_eq!(restored, 1);
+    assert_eq!(first.object(PACK), second.object(PACK));
+
+    // A replaced disk is filled from the vault
+    for key in second.keys() {
+        second.delete(&key).unwrap();
+    }
+    let replicas = vault.storage.backend().replicas();
+    let (copied, deleted) = resync(&replicas, &mut NoProgress).unwrap();
+    assert_eq!((copied, deleted), (first.keys().len(), 0));
+    assert_eq!(first.keys(), second.keys());
+}
+
+#[test]
+fn stale_mirror_follows_the_vault() {
+    let (first, second) = (MemoryBackend::new(), MemoryBackend::new());
+    let mut vault = Vault::in_memory(first.clone()).unwrap();
+    backup_src(&mut vault);
+    let replicas: [&dyn Backend; 2] = [&first, &second];
+    resync(&replicas, &mut NoProgress).unwrap();
+    assert_eq!(first.keys(), second.keys());
+
+    // While the mirror is offline, the backup is emptied and its pack repacked away
+    vault.database.insert_backup(BACKUP, Backup::new());
+    vault.write_database().unwrap();
+    vault.storage.repack(&HashSet::new()).unwrap();
+    assert!(first.object(PACK).is_none());
+    drop(vault);
+
+    let vault = open_mirrored(&first, &second);
+    let live = live_blobs(&vault);
+    assert_eq!(
+        vault
+            .storage
+            .restore_primary(&live, &mut NoProgress)
+            .unwrap(),
+        0
+    );
+    let replicas = vault.storage.backend().replicas();
+    let (copied, deleted) = resync(&replicas, &mut NoProgress).unwrap();
+    assert_eq!(copied, 1);
+    assert!(deleted >= 2);
+    assert_eq!(first.keys(), second.keys());
+    assert!(second.object(PACK).is_none());
+    assert_eq!(
+        first.object("database.json"),
+        second.object("database.json")
+    );
+}
+
+#[test]
+fn init_checks_every_mirror_before_creating_anything() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let mirror = mktemp::Temp::new_dir().unwrap();
+    let not_empty = mktemp::Temp::new_dir().unwrap();
+    fs::write(not_empty.join("file"), "").unwrap();
+    let init = |mirrors: &[&Path]| {
+        let mut args = vec!["init", "-v", vault.to_str().unwrap()];
+        for dir in mirrors {
+            args.extend(["--mirror", dir.to_str().unwrap()]);
+        }
+        sharedfileholder::main_with_args(&args)
+    };
+
+    assert!(init(&[&mirror, &not_empty]).is_err());
+    assert!(init(&[&mirror, &vault.join(".")]).is_err());
+    assert!(init(&[&mirror, &mirror]).is_err());
+    for dir in [&vault, &mirror] {
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+    }
+
+    init(&[&mirror]).unwrap();
+    let config = Config::load(&LocalBackend::new(&vault)).unwrap();
+    assert_eq!(config.mirrors, [fs::canonicalize(&mirror).unwrap()]);
+}
+
+#[test]
+fn resync_leaves_other_files_alone() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let mirror = mktemp::Temp::new_dir().unwrap();
+    let vault_dir = vault.to_str().unwrap();
+    let mirror_dir = mirror.to_str().unwrap();
+    let run = |args: &[&str]| {
+        let args = [&["-v", vault_dir], args].concat();
+        sharedfileholder::main_with_args(&args)
+    };
+    run(&["init"]).unwrap();
+    run(&["backup", BACKUP, "src"]).unwrap();
+
+    // A directory with files of its own isn't turned into a mirror
+    fs::create_dir(mirror.join("photos")).unwrap();
+    fs::write(mirror.join("photos/cat.jpg"), "meow").unwrap();
+    assert!(run(&["resync", "--add", mirror_dir]).is_err());
+    assert!(mirror.join("photos/cat.jpg").exists());
+    assert!(!mirror.join("config.json").exists());
+    assert!(Config::load(&LocalBackend::new(&vault))
+        .unwrap()
+        .mirrors
+        .is_empty());
+
+    fs::remove_dir_all(mirror.join("photos")).unwrap();
+    run(&["resync", "--add", mirror_dir]).unwrap();
+    assert!(mirror.join("config.json").exists());
+
+    // Files added to a mirror later aren't the vault's, so they are kept too
+    fs::create_dir(mirror.join("photos")).unwrap();
+    fs::write(mirror.join("photos/cat.jpg"), "meow").unwrap();
+    run(&["resync"]).unwrap();
+    assert!(mirror.join("photos/cat.jpg").exists());
+    // It holds a copy of the vault, so adding it again is fine
+    run(&["resync", "--add", mirror_dir]).unwrap();
+}
+
+#[test]
+fn resync_refuses_a_mirror_without_the_vault() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let mirror = mktemp::Temp::new_dir().unwrap();
+    let vault_dir = vault.to_str().unwrap();
+    let mirror_dir = mirror.to_str().unwrap();
+    let run = |args: &[&str]| {
+        let args = [&["-v", vault_dir], args].concat();
+        sharedfileholder::main_with_args(&args)
+    };
+    run(&["init", "--mirror", mirror_dir]).unwrap();
+    run(&["backup", BACKUP, "src"]).unwrap();
+
+    // Like the empty mount point of a disk that isn't mounted
+    fs::remove_dir_all(&*mirror).unwrap();
+    fs::create_dir(&*mirror).unwrap();
+    assert!(run(&["resync"]).is_err());
+    assert_eq!(fs::read_dir(&*mirror).unwrap().count(), 0);
+    // Backups go on without it
+    run(&["backup", BACKUP, "src"]).unwrap();
+    assert_eq!(fs::read_dir(&*mirror).unwrap().count(), 0);
+
+    // A mirror of another vault isn't one of this vault
+    let other = mktemp::Temp::new_dir().unwrap();
+    let other_dir = other.to_str().unwrap();
+    sharedfileholder::main_with_args(&[
+        "init", "-v", other_dir, "--mirror", mirror_dir, "--parity", "10",
+    ])
+    .unwrap();
+    assert!(run(&["resync"]).is_err());
+    assert!(run(&["resync", "--add", mirror_dir]).is_err());
+}
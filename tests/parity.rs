@@ -0,0 +1,61 @@
+mod common;
+
+use common::{backup_src, lib_rs};
+use sharedfileholder::{
+    progress::NoProgress,
+    vault::{
+        backend::{Backend, MemoryBackend},
+        config::Config,
+        Vault,
+    },
+};
+
+fn vault_with_parity(percent: u8) -> (Vault, MemoryBackend) {
+    let backend = MemoryBackend::new();
+    let config = Config {
+        parity: Some(percent),
+        ..Default::default()
+    };
+    let mut vault = Vault::create(Box::new(backend.clone()), config, None).unwrap();
+    backup_src(&mut vault);
+    (vault, backend)
+}
+
+#[test]
+fn rebuild_corrupted_pack_from_parity() {
+    let (vault, backend) = vault_with_parity(10);
+    let keys = backend.keys();
+    assert!(keys
+        .iter()
+        .any(|key| key == "parity/packs/00000000.pack.par"));
+
+    let lib = lib_rs(&vault);
+    let original = vault.storage.read_blob(lib).unwrap();
+    let mut pack = backend.object("packs/00000000.pack").unwrap();
+    for byte in &mut pack[..100] {
+        *byte ^= 0xff;
+    }
+    backend.set_object("packs/00000000.pack", &pack);
+    assert!(vault.storage.repair_from_parity(lib, true).unwrap());
+    assert_eq!(backend.object("packs/00000000.pack").unwrap(), pack);
+
+    assert!(vault.storage.repair_from_parity(lib, false).unwrap());
+    assert_eq!(vault.storage.read_blob(lib).unwrap(), original);
+    assert!(backend.object("quarantine/00000000.pack").is_some());
+    // Nothing left to rebuild
+    assert!(!vault.storage.repair_from_parity(lib, false).unwrap());
+}
+
+#[test]
+fn rebuild_missing_pack_from_parity() {
+    let (vault, backend) = vault_with_parity(100);
+    let lib = lib_rs(&vault);
+    let original = vault.storage.read_blob(lib).unwrap();
+    backend.delete("packs/00000000.pack").unwrap();
+
+    // Adding parity keeps the parity of the missing pack
+    vault.storage.add_parity(100, &mut NoProgress).unwrap();
+    assert!(backend.object("parity/packs/00000000.pack.par").is_some());
+    assert!(vault.storage.repair_from_parity(lib, false).unwrap());
+    assert_eq!(vault.storage.read_blob(lib).unwrap(), original);
+}
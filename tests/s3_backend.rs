@@ -0,0 +1,221 @@
+mod common;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Read,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use common::{backup_src, pseudo_random, BACKUP};
+use percent_encoding::percent_decode_str;
+use sharedfileholder::{
+    progress::NoProgress,
+    restore_from,
+    vault::{
+        backend::{Backend, S3Backend, S3Config},
+        config::Config,
+        Vault,
+    },
+};
+use tiny_http::{Header, Request, Response, Server};
+
+const BUCKET: &str = "bucket";
+/// Small, so that listing has to go through several pages
+const LIST_PAGE_SIZE: usize = 3;
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// Just enough of the S3 API for S3Backend, keeping objects in memory.
+fn start_mock_s3() -> (String, Objects) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let objects = Objects::default();
+    let served = objects.clone();
+    thread::spawn(move || {
+        let mut uploads: HashMap<String, BTreeMap<u32, Vec<u8>>> = HashMap::new();
+        for request in server.incoming_requests() {
+            handle(request, &served, &mut uploads);
+        }
+    });
+    (format!("http://127.0.0.1:{port}"), objects)
+}
+
+fn handle(
+    mut request: Request,
+    objects: &Objects,
+    uploads: &mut HashMap<String, BTreeMap<u32, Vec<u8>>>,
+) {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.to_string())
+    };
+    let authorized = header("Authorization")
+        .is_some_and(|auth| auth.starts_with("AWS4-HMAC-SHA256 Credential=test-key/"));
+    let range = header("Range");
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query: HashMap<String, String> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s| percent_decode_str(s).decode_utf8().unwrap().into_owned();
+            (decode(name), decode(value))
+        })
+        .collect();
+    let path = percent_decode_str(path).decode_utf8().unwrap();
+    let key = path
+        .strip_prefix(&format!("/{BUCKET}"))
+        .map(|key| key.trim_start_matches('/').to_owned());
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body).unwrap();
+
+    let method = request.method().as_str().to_owned();
+    let response = match (method.as_str(), key) {
+        _ if !authorized => {
+            Response::from_data("<Error><Code>AccessDenied</Code></Error>").with_status_code(403)
+        }
+        (_, None) => {
+            Response::from_data("<Error><Code>NoSuchBucket</Code></Error>").with_status_code(404)
+        }
+        ("GET", Some(key)) if key.is_empty() => {
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let after = query.get("continuation-token").cloned().unwrap_or_default();
+            let objects = objects.lock().unwrap();
+            let keys: Vec<_> = objects
+                .keys()
+                .filter(|key| key.starts_with(&prefix) && **key > after)
+                .take(LIST_PAGE_SIZE + 1)
+                .collect();
+            let mut xml = String::from("<ListBucketResult>");
+            for key in keys.iter().take(LIST_PAGE_SIZE) {
+                xml += &format!("<Contents><Key>{key}</Key></Contents>");
+            }
+            if keys.len() > LIST_PAGE_SIZE {
+                let last = keys[LIST_PAGE_SIZE - 1];
+                xml += &format!("<NextContinuationToken>{last}</NextContinuationToken>");
+            }
+            xml += "</ListBucketResult>";
+            Response::from_data(xml)
+        }
+        ("POST", Some(_)) if query.contains_key("uploads") => {
+            let id = format!("upload-{}", uploads.len());
+            uploads.insert(id.clone(), BTreeMap::new());
+            Response::from_data(format!(
+                "<InitiateMultipartUploadResult><UploadId>{id}</UploadId></InitiateMultipartUploadResult>"
+            ))
+        }
+        ("PUT", Some(_)) if query.contains_key("uploadId") => {
+            let number: u32 = query["partNumber"].parse().unwrap();
+            let parts = uploads.get_mut(&query["uploadId"]).unwrap();
+            parts.insert(number, body);
+            let etag = Header::from_bytes("ETag", format!("\"etag-{number}\"")).unwrap();
+            Response::from_data(Vec::new()).with_header(etag)
+        }
+        ("POST", Some(key)) if query.contains_key("uploadId") => {
+            let parts = uploads.remove(&query["uploadId"]).unwrap();
+            let complete = String::from_utf8(body).unwrap();
+            assert_eq!(complete.matches("<Part>").count(), parts.len());
+            let data = parts.into_values().flatten().collect();
+            objects.lock().unwrap().insert(key, data);
+            Response::from_data("<CompleteMultipartUploadResult/>")
+        }
+        ("PUT", Some(key)) => {
+            objects.lock().unwrap().insert(key, body);
+            Response::from_data(Vec::new())
+        }
+        ("GET" | "HEAD", Some(key)) => match objects.lock().unwrap().get(&key) {
+            Some(data) => {
+                let data = match range.as_deref().and_then(|r| r.strip_prefix("bytes=")) {
+                    Some(range) => {
+                        let (start, end) = range.split_once('-').unwrap();
+                        data[start.parse().unwrap()..=end.parse().unwrap()].to_vec()
+                    }
+                    None => data.clone(),
+                };
+                Response::from_data(data)
+            }
+            None => {
+                Response::from_data("<Error><Code>NoSuchKey</Code></Error>").with_status_code(404)
+            }
+        },
+        ("DELETE", Some(key)) => {
+            objects.lock().unwrap().remove(&key);
+            Response::from_data(Vec::new()).with_status_code(204)
+        }
+        _ => Response::from_data(Vec::new()).with_status_code(400),
+    };
+    request.respond(response).unwrap();
+}
+
+fn backend(endpoint: &str) -> S3Backend {
+    S3Backend::new(S3Config {
+        endpoint: endpoint.to_owned(),
+        region: "us-east-1".to_owned(),
+        bucket: BUCKET.to_owned(),
+        prefix: "vault/".to_owned(),
+        access_key: "test-key".to_owned(),
+        secret_key: "test-secret".to_owned(),
+        session_token: None,
+    })
+    .unwrap()
+}
+
+#[test]
+fn objects() {
+    let (endpoint, objects) = start_mock_s3();
+    let backend = backend(&endpoint);
+
+    // Large enough to be uploaded in parts
+    let large = pseudo_random(20 << 20);
+    backend.put("packs/large", &large).unwrap();
+    assert_eq!(objects.lock().unwrap()["vault/packs/large"], large);
+    let mut read = Vec::new();
+    backend
+        .get("packs/large")
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, large);
+    let mut range = Vec::new();
+    let mut reader = backend.get_range("packs/large", 1000, 10).unwrap();
+    reader.read_to_end(&mut range).unwrap();
+    assert_eq!(range, &large[1000..1010]);
+
+    for i in 0..10 {
+        backend.put(&format!("data/{i:02}"), b"x").unwrap();
+    }
+    let keys = backend.list("data/").unwrap();
+    assert_eq!(keys.len(), 10);
+    assert_eq!(keys[0], "data/00");
+
+    assert!(backend.exists("data/00").unwrap());
+    backend.delete("data/00").unwrap();
+    assert!(!backend.exists("data/00").unwrap());
+    assert!(backend.get("data/00").unwrap().is_none());
+    // Deleting again is fine
+    backend.delete("data/00").unwrap();
+}
+
+#[test]
+fn backup_and_restore() {
+    let (endpoint, objects) = start_mock_s3();
+    let mut vault = Vault::create(Box::new(backend(&endpoint)), Config::default(), None).unwrap();
+    backup_src(&mut vault);
+    drop(vault);
+    assert!(objects.lock().unwrap().contains_key("vault/database.json"));
+
+    let vault = Vault::open_backend(Box::new(backend(&endpoint)), None).unwrap();
+    let dest = mktemp::Temp::new_dir().unwrap();
+    restore_from(&vault, BACKUP, &dest, &[], &mut NoProgress).unwrap();
+    assert_eq!(
+        fs::read(dest.join("src/lib.rs")).unwrap(),
+        fs::read("src/lib.rs").unwrap()
+    );
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use std::{
+    collections::HashSet, ffi::CString, fs, io::Read, mem, os::unix::ffi::OsStrExt, path::Path, ptr,
+};
+
+use common::{pseudo_random, RecordingProgress};
+use sharedfileholder::{
+    progress::NoProgress,
+    vault::{backend::MemoryBackend, config::Config, pack::PackEntry, storage::Storage, Vault},
+    Hash,
+};
+
+#[test]
+fn chunks_survive_appending() {
+    let vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let mut data = pseudo_random(20 << 20);
+    let before = vault
+        .storage
+        .insert_chunked(data.as_slice(), &mut NoProgress)
+        .unwrap();
+    assert!(before.chunks.len() > 2);
+
+    data.extend_from_slice(b"appended");
+    let after = vault
+        .storage
+        .insert_chunked(data.as_slice(), &mut NoProgress)
+        .unwrap();
+    assert_ne!(before.hash, after.hash);
+    // Only the last chunk changes
+    let n = before.chunks.len();
+    assert_eq!(before.chunks[..n - 1], after.chunks[..n - 1]);
+    assert_ne!(before.chunks[n - 1], after.chunks[n - 1]);
+
+    let mut read = Vec::new();
+    std::io::copy(&mut vault.storage.open_blobs(&after.chunks), &mut read).unwrap();
+    assert!(read == data);
+}
+
+#[test]
+fn repack_drops_only_dead_blobs() {
+    let backend = MemoryBackend::new();
+    let vault = Vault::in_memory(backend.clone()).unwrap();
+    let blobs: Vec<Vec<u8>> = (0..10).map(|i| pseudo_random(1000 + i)).collect();
+    let hashes: Vec<Hash> = blobs
+        .iter()
+        .map(|blob| {
+            vault
+                .storage
+                .insert_reader(blob.as_slice(), &mut NoProgress)
+                .unwrap()
+        })
+        .collect();
+    vault.storage.flush().unwrap();
+
+    let live: HashSet<Hash> = hashes.iter().step_by(2).copied().collect();
+    let stats = vault.storage.repack(&live).unwrap();
+    assert_eq!(stats.blobs_dropped, 5);
+    assert_eq!(stats.packs_rewritten, 1);
+    assert!(!backend.keys().contains(&"packs/00000000.pack".to_string()));
+    drop(vault);
+
+    let vault = Vault::open_backend(Box::new(backend), None).unwrap();
+    for (blob, hash) in blobs.iter().zip(&hashes) {
+        if live.contains(hash) {
+            assert_eq!(&vault.storage.read_blob(*hash).unwrap(), blob);
+        } else {
+            assert!(!vault.storage.contains(*hash).unwrap());
+        }
+    }
+}
+
+#[test]
+fn compressed_blobs_round_trip() {
+    let backend = MemoryBackend::new();
+    let config = Config {
+        compression_level: Some(3),
+        ..Default::default()
+    };
+    let vault = Vault::create(Box::new(backend.clone()), config, None).unwrap();
+    // The first two get objects of their own even once compressed, the others are packed
+    let compressible = |len| -> Vec<u8> { pseudo_random(len).iter().map(|b| b & 0x0f).collect() };
+    let blobs = [
+        (compressible(1 << 20), true),
+        (pseudo_random(1 << 18), false),
+        (compressible(1000), true),
+        (pseudo_random(1001), false),
+    ];
+    let hashes: Vec<Hash> = blobs
+        .iter()
+        .map(|(blob, _)| {
+            vault
+                .storage
+                .insert_reader(blob.as_slice(), &mut NoProgress)
+                .unwrap()
+        })
+        .collect();
+    vault.storage.flush().unwrap();
+
+    for ((blob, compressed), hash) in blobs[..2].iter().zip(&hashes) {
+        let key = Storage::object_key(*hash, false);
+        match compressed {
+            true => {
+                let stored = backend.object(&Storage::object_key(*hash, true)).unwrap();
+                assert!(stored.len() < blob.len());
+                assert!(backend.object(&key).is_none());
+            }
+            false => assert_eq!(&backend.object(&key).unwrap(), blob),
+        }
+    }
+    let index = backend.object("packs/00000000.idx").unwrap();
+    let entries: Vec<PackEntry> = serde_json::Deserializer::from_slice(&index)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    for ((_, compressed), hash) in blobs[2..].iter().zip(&hashes[2..]) {
+        let entry = entries.iter().find(|entry| entry.hash == *hash).unwrap();
+        assert_eq!(entry.compressed, *compressed);
+    }
+    drop(vault);
+
+    let vault = Vault::open_backend(Box::new(backend), None).unwrap();
+    for ((blob, _), hash) in blobs.iter().zip(&hashes) {
+        assert_eq!(&vault.storage.read_blob(*hash).unwrap(), blob);
+    }
+}
+
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Returns how many times path was opened while running f, as reported by inotify.
+fn count_opens(path: &Path, f: impl FnOnce()) -> usize {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    // Closes are watched too, since inotify merges identical events that follow each other
+    let mask = libc::IN_OPEN | libc::IN_CLOSE;
+    // SAFETY: plain syscalls on a file descriptor owned here, with a buffer that outlives them
+    unsafe {
+        let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+        assert!(fd >= 0);
+        assert!(libc::inotify_add_watch(fd, path.as_ptr(), mask) >= 0);
+        f();
+        let mut opens = 0;
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = libc::read(fd, buf.as_mut_ptr().cast(), buf.len());
+            if n <= 0 {
+                break;
+            }
+            // Events on a watched file have no name, so they are all the same size
+            let size = mem::size_of::<libc::inotify_event>();
+            for event in buf[..n as usize].chunks_exact(size) {
+                let event: libc::inotify_event = ptr::read_unaligned(event.as_ptr().cast());
+                if event.mask & libc::IN_OPEN != 0 {
+                    opens += 1;
+                }
+            }
+        }
+        libc::close(fd);
+        opens
+    }
+}
+
+#[test]
+fn files_are_read_once() {
+    let tmp = mktemp::Temp::new_dir().unwrap();
+    let vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    // Small enough to be stored as one blob, and large enough to be chunked
+    for len in [100 << 10, 12 << 20] {
+        let data = pseudo_random(len);
+        let path = tmp.join(format!("file-{len}"));
+        fs::write(&path, &data).unwrap();
+
+        let mut progress = RecordingProgress::default();
+        let mut stored = None;
+        let opens = count_opens(&path, || {
+            stored = Some(vault.storage.insert_file(&path, &mut progress).unwrap());
+        });
+        assert_eq!(opens, 1);
+        let stored = stored.unwrap();
+        assert_eq!(stored.hash, Hash::from(blake3::hash(&data)));
+        assert_eq!(progress.hashed, len as u64);
+        assert_eq!(progress.copied, len as u64);
+
+        let mut read = Vec::new();
+        if stored.chunks.is_empty() {
+            read = vault.storage.read_blob(stored.hash).unwrap();
+        } else {
+            std::io::copy(&mut vault.storage.open_blobs(&stored.chunks), &mut read).unwrap();
+        }
+        assert!(read == data);
+    }
+}
+
+#[test]
+fn readers_are_read_once() {
+    let vault = Vault::in_memory(MemoryBackend::new()).unwrap();
+    let data = pseudo_random(12 << 20);
+    let mut reader = CountingReader {
+        inner: data.as_slice(),
+        count: 0,
+    };
+    let stored = vault
+        .storage
+        .insert_chunked(&mut reader, &mut NoProgress)
+        .unwrap();
+    assert_eq!(reader.count, data.len() as u64);
+    assert_eq!(stored.hash, Hash::from(blake3::hash(&data)));
+
+    // Contents that are already stored are hashed, but not copied again
+    let mut reader = CountingReader {
+        inner: &data[..1000],
+        count: 0,
+    };
+    let mut progress = RecordingProgress::default();
+    vault
+        .storage
+        .insert_reader(&mut reader, &mut progress)
+        .unwrap();
+    vault
+        .storage
+        .insert_reader(&data[..1000], &mut progress)
+        .unwrap();
+    assert_eq!(reader.count, 1000);
+    assert_eq!(progress.hashed, 2000);
+    assert_eq!(progress.copied, 1000);
+}
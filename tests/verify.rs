@@ -0,0 +1,145 @@
+mod common;
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use common::pseudo_random;
+
+fn run(vault: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_backup"))
+        .arg("-v")
+        .arg(vault)
+        .args(args)
+        .output()
+        .unwrap();
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// Creates a vault with the init arguments, backs up a file large enough to get an object
+/// of its own, and a small one that is packed, and returns the object of the large one.
+fn backup_two_files(vault: &Path, src: &Path, init: &[&str]) -> PathBuf {
+    let large = pseudo_random(1 << 18);
+    fs::write(src.join("large"), large).unwrap();
+    fs::write(src.join("small"), "small").unwrap();
+    assert!(run(vault, &[&["init"], init].concat()).status.success());
+    assert!(run(vault, &["backup", "b", src.to_str().unwrap()])
+        .status
+        .success());
+
+    let data_dir = fs::read_dir(vault.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.is_dir())
+        .unwrap();
+    let object = fs::read_dir(data_dir).unwrap().next().unwrap().unwrap();
+    object.path()
+}
+
+fn corrupt(path: &Path) {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+    let mut data = fs::read(path).unwrap();
+    data[100] ^= 0xff;
+    fs::write(path, data).unwrap();
+}
+
+#[test]
+fn verify_reports_damaged_blobs() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let src = mktemp::Temp::new_dir().unwrap();
+    let object = backup_two_files(&vault, &src, &[]);
+    assert!(run(&vault, &["verify"]).status.success());
+
+    corrupt(&object);
+    fs::remove_dir_all(vault.join("packs")).unwrap();
+    let output = run(&vault, &["verify"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (corrupted, missing) = stdout
+        .split_once("Corrupted blobs:\n")
+        .unwrap()
+        .1
+        .split_once("Missing blobs:\n")
+        .unwrap();
+    assert!(corrupted.contains("    b: large\n"), "{stdout}");
+    assert!(missing.contains("    b: small\n"), "{stdout}");
+}
+
+#[test]
+fn repair_from_original_files() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let src = mktemp::Temp::new_dir().unwrap();
+    let object = backup_two_files(&vault, &src, &[]);
+    corrupt(&object);
+    let corrupted = fs::read(&object).unwrap();
+
+    let output = run(&vault, &["repair", "--from", src.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Repaired 1 of 1 damaged blobs"), "{stdout}");
+    let quarantined = vault.join("quarantine").join(object.file_name().unwrap());
+    assert_eq!(fs::read(quarantined).unwrap(), corrupted);
+    assert!(run(&vault, &["verify"]).status.success());
+}
+
+#[test]
+fn verify_and_repair_a_damaged_mirror_copy() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let mirror = mktemp::Temp::new_dir().unwrap();
+    let src = mktemp::Temp::new_dir().unwrap();
+    let object = backup_two_files(&vault, &src, &["--mirror", mirror.to_str().unwrap()]);
+    let in_mirror = mirror.join(object.strip_prefix(&*vault).unwrap());
+    let intact = fs::read(&object).unwrap();
+    corrupt(&in_mirror);
+
+    // Reads fall back to the vault's copy, but verify still finds the mirror's
+    let output = run(&vault, &["verify"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let damaged = stdout
+        .split_once("Damaged copies, intact in another copy:\n")
+        .unwrap()
+        .1;
+    let expected = format!("in copy 2 of 2, {}\n    b: large\n", in_mirror.display());
+    assert!(damaged.contains(&expected), "{stdout}");
+
+    let output = run(&vault, &["repair"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Rewrote 1 of 1 damaged copies"), "{stdout}");
+    assert_eq!(fs::read(&in_mirror).unwrap(), intact);
+    assert!(!vault.join("quarantine").exists());
+    assert!(run(&vault, &["verify"]).status.success());
+}
+
+#[test]
+fn unreadable_pack_index() {
+    let vault = mktemp::Temp::new_dir().unwrap();
+    let src = mktemp::Temp::new_dir().unwrap();
+    backup_two_files(&vault, &src, &[]);
+    let index = vault.join("packs/00000000.idx");
+    corrupt(&index);
+
+    // The vault still opens, with the packed blob missing
+    let output = run(&vault, &["verify"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Missing blobs:\n"), "{stdout}");
+    assert!(stdout.contains("    b: small\n"), "{stdout}");
+    let unreadable = "Unreadable pack indexes, whose blobs are missing:\n- packs/00000000.idx\n";
+    assert!(stdout.contains(unreadable), "{stdout}");
+    // Repacking doesn't take it for a pack that was never finished
+    assert!(run(&vault, &["repack"]).status.success());
+    assert!(vault.join("packs/00000000.pack").is_file());
+
+    assert!(run(&vault, &["repair", "--from", src.to_str().unwrap()])
+        .status
+        .success());
+    assert!(vault.join("quarantine/00000000.idx").is_file());
+    assert!(!index.exists());
+    assert!(run(&vault, &["verify"]).status.success());
+}